@@ -1,63 +1,165 @@
+use std::{path::Path, time::Duration};
+
 use tracing::{debug, trace};
 
 use libchat::{
     err::{MyError, MyResult},
-    sys::{ClientSocket, SockAddr, SocketCommon},
-    ServerReply, COMMAND_MAX, COMMAND_SEP, HANDSHAKE_ACK, REPLY_FLAG_ERR,
+    protocol::{
+        Command, Feature, HandshakeReply, Hello, ServerMsg, PROTOCOL_VERSION,
+        PROTOCOL_VERSION_MIN,
+    },
+    sys::{resolve, ClientSocket, SockAddr, SocketCommon, UnixClientSocket},
 };
 
 /// Wrapper type that manages client-side networking.
 ///
+/// The client works the same over any kind of socket, e.g. a TCP
+/// `ClientSocket` (see `connect_tcp()`) or a `UnixClientSocket` (see
+/// `connect_unix()`).
+///
 /// Methods are provided for sending a command to the server (`send_cmd`) and
-/// receiving the reply (`recv_reply`).
+/// receiving messages from the server (`recv_msg`).
 ///
 /// A command invocation must be implemented as one call to `send_cmd()`
-/// followed by one call to `recv_reply()`. Special control bytes are used as
-/// delimiters for command arguments and reply status and information.
-pub struct TcpClient {
-    pub sock: ClientSocket,
+/// followed by calls to `recv_msg()` until a reply is received. The server may
+/// push any number of messages before the reply. Messages are encoded and
+/// decoded with the types in `libchat::protocol`.
+pub struct ChatClient<S: SocketCommon> {
+    pub sock: S,
+    /// The name the server introduced itself with.
+    pub server_name: String,
+    /// The protocol version negotiated with the server.
+    pub version: u32,
+    /// The features enabled by the server for this connection.
+    pub features: Vec<Feature>,
 }
 
-impl TcpClient {
+impl ChatClient<ClientSocket> {
     /// Create a new TCP client which immediately attempts to connect to the
-    /// server.
-    pub fn new(port: u16) -> MyResult<Self> {
-        let sock = ClientSocket::new()?;
-        let mut addr = SockAddr::new(port);
-        sock.connect(&mut addr)?;
-        let reply = sock.recv(COMMAND_MAX)?;
-        debug!(msg = ?reply, "handshake reply");
-        if reply == HANDSHAKE_ACK {
-            Ok(Self { sock })
-        } else {
-            Err(MyError::ClientRejected)
+    /// server and perform the handshake.
+    ///
+    /// `host` may be a host name or an IPv4 or IPv6 address. Every address it
+    /// resolves to is tried in turn until a connection is established, giving
+    /// up on each one after `timeout`. If none of them can be reached, the
+    /// error lists every address along with why it failed.
+    pub fn connect_tcp(
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> MyResult<Self> {
+        Self::handshake(Self::connect_any(host, port, timeout)?)
+    }
+
+    /// Connect to the first address of `host` that accepts the connection.
+    fn connect_any(
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> MyResult<ClientSocket> {
+        let mut failures = Vec::new();
+        for addr in resolve(host, port)? {
+            let addr = SockAddr::from(addr);
+            let res = ClientSocket::new(addr.family()).and_then(|sock| {
+                sock.connect_timeout(&addr, timeout).map(|_| sock)
+            });
+            match res {
+                Ok(sock) => {
+                    debug!(%addr, "connected");
+                    return Ok(sock);
+                }
+                Err(error) => {
+                    debug!(%addr, %error, "failed to connect");
+                    failures.push(error.to_string());
+                }
+            }
+        }
+
+        Err(format!(
+            "could not connect to {} port {}:\n  {}",
+            host,
+            port,
+            failures.join("\n  ")
+        )
+        .into())
+    }
+}
+
+impl ChatClient<UnixClientSocket> {
+    /// Create a new client which immediately attempts to connect to the
+    /// server through the Unix domain socket at `path` and perform the
+    /// handshake.
+    pub fn connect_unix(path: impl AsRef<Path>) -> MyResult<Self> {
+        let sock = UnixClientSocket::new()?;
+        sock.connect(path)?;
+        Self::handshake(sock)
+    }
+}
+
+impl<S: SocketCommon> ChatClient<S> {
+    /// Perform the handshake over the newly connected socket `sock`.
+    ///
+    /// The client announces the newest protocol version and every feature it
+    /// supports, and the server replies with what it agreed to.
+    fn handshake(sock: S) -> MyResult<Self> {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            features: Feature::ALL.to_vec(),
+        };
+        sock.send(hello.encode())?;
+
+        let reply = HandshakeReply::decode(&sock.recv()?)?;
+        debug!(?reply, "handshake reply");
+        match reply {
+            HandshakeReply::Welcome { version, .. }
+                if !(PROTOCOL_VERSION_MIN..=PROTOCOL_VERSION)
+                    .contains(&version) =>
+            {
+                Err(MyError::Protocol(format!(
+                    "server chose unsupported protocol version {}",
+                    version
+                )))
+            }
+            HandshakeReply::Welcome {
+                version,
+                server_name,
+                features,
+            } => Ok(Self {
+                sock,
+                server_name,
+                version,
+                features,
+            }),
+            HandshakeReply::Reject { reason, message } => {
+                Err(MyError::ClientRejected { reason, message })
+            }
         }
     }
 
     /// Send the given command to the server.
+    pub fn send_cmd(&self, cmd: &Command) -> MyResult<()> {
+        self.sock.send(cmd.encode())
+    }
+
+    /// Return the next message from the server, which is either the reply
+    /// indicating whether the previous command succeeded or failed, or a push.
     ///
-    /// The command name and arguments are separated by a special byte that is
-    /// expected by the server.
+    /// This blocks until a message arrives.
+    pub fn recv_msg(&self) -> MyResult<ServerMsg> {
+        Self::decode_msg(self.sock.recv()?)
+    }
+
+    /// Return the next message from the server if one has already been
+    /// received completely, without blocking.
     ///
-    /// For example, if the separator is "|" and the command parts are
-    /// ["cmd", "arg1", "arg2"], then "cmd|arg1|arg2" is sent. If the command
-    /// parts are ["cmd"], then "cmd" is sent with no separators.
-    pub fn send_cmd<'a>(&self, parts: impl AsRef<[&'a str]>) -> MyResult<()> {
-        self.sock.send(parts.as_ref().join(COMMAND_SEP))
-    }
-
-    /// Return the reply from the server indicating whether the previous command
-    /// succeeded or failed.
-    pub fn recv_reply(&self) -> MyResult<ServerReply> {
-        let msg = self.sock.recv(COMMAND_MAX)?;
-        trace!(msg = ?msg, "server response");
-        let msg_b = msg.as_bytes();
-        if !msg_b.is_empty() && msg_b[0] == REPLY_FLAG_ERR {
-            // Received string with error flag for first byte
-            Ok(Err(String::from_utf8_lossy(&msg_b[1..]).to_string()))
-        } else {
-            // Received non-error string
-            Ok(Ok(String::from_utf8_lossy(msg_b).to_string()))
-        }
+    /// Use `SocketCommon::fill_recv_buf()` to read more data once the socket
+    /// is ready for reading.
+    pub fn recv_buffered_msg(&self) -> MyResult<Option<ServerMsg>> {
+        self.sock.recv_buffered()?.map(Self::decode_msg).transpose()
+    }
+
+    #[inline]
+    fn decode_msg(msg: String) -> MyResult<ServerMsg> {
+        trace!(msg = ?msg, "server message");
+        ServerMsg::decode(&msg)
     }
 }
@@ -0,0 +1,452 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use libchat::{
+    config::{file_parse, pick, var_opt},
+    err::MyResult,
+    logging::LogFilter,
+    CHAT_PORT,
+};
+
+/// The server to connect to if no host is given anywhere.
+const DEFAULT_HOST: &str = "127.0.0.1";
+
+/// Path of the config file relative to the user's config directory.
+const CONFIG_FILE: &str = "chat-boat/client.toml";
+
+/// The log filter if neither `RUST_LOG` nor `LOG_LEVEL` is set. Only warnings
+/// and errors are printed, so that log messages don't get in the way of the
+/// chat.
+const DEFAULT_LOG_FILTER: &str = "warn";
+
+//==============================================================================
+// Command line
+//==============================================================================
+
+/// Connect to a Chat Boat server and chat interactively.
+///
+/// Options that are not given fall back to the environment (which includes
+/// the `.env` file), then to the selected profile of the config file, and then
+/// to the defaults.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Read profiles from this TOML file [env: CHAT_CLIENT_CONFIG]
+    /// [default: ~/.config/chat-boat/client.toml]
+    #[arg(long, short, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Use the settings of this profile of the config file [env: CHAT_PROFILE]
+    /// [default: `default_profile` of the config file]
+    #[arg(long, short = 'P', value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Host name or IP address of the server [env: CHAT_HOST]
+    /// [default: 127.0.0.1]
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port of the server [env: CHAT_PORT] [default: 10087]
+    #[arg(long, short)]
+    pub port: Option<u16>,
+
+    /// Connect through the Unix domain socket at this path instead of TCP
+    /// [env: CHAT_SOCKET]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["host", "port"])]
+    pub socket: Option<PathBuf>,
+
+    /// User name to log in as, so that `login` only needs the password. If
+    /// the profile also has credentials, log in right after connecting
+    /// [env: CHAT_USER]
+    #[arg(long, short)]
+    pub username: Option<String>,
+
+    /// When to use colors, one of `auto`, `always` or `never`
+    /// [default: auto]
+    #[arg(long, value_name = "WHEN")]
+    pub color: Option<ColorMode>,
+
+    /// Which log messages to print, either a level (`off`, `error`, `warn`,
+    /// `info`, `debug` or `trace`) or directives like `RUST_LOG`, e.g.
+    /// `warn,libchat=debug` [env: RUST_LOG, LOG_LEVEL] [default: warn]
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<LogFilter>,
+
+    /// Append log messages to this file instead of printing them
+    /// [env: LOG_FILE]
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+}
+
+/// When to print colors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Only if standard output is a terminal and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("unknown color mode: {}", s)),
+        }
+    }
+}
+
+//==============================================================================
+// Config file
+//==============================================================================
+
+/// The contents of the config file: named profiles, and which one to use if
+/// none is selected, e.g.
+///
+/// ```toml
+/// default_profile = "work"
+///
+/// [profiles.work]
+/// host = "chat.internal"
+/// username = "alice"
+/// password_command = "pass show chat/work"
+///
+/// [profiles.local]
+/// socket = "/run/chat/chat.sock"
+/// color = "never"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    default_profile: Option<String>,
+    profiles: BTreeMap<String, Profile>,
+}
+
+/// The settings of one profile. Every key is optional.
+///
+/// Values that are parsed from text, like the log level, are kept as strings
+/// until they are parsed along with the environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Profile {
+    host: Option<String>,
+    port: Option<u16>,
+    socket: Option<PathBuf>,
+    username: Option<String>,
+    password: Option<String>,
+    password_command: Option<String>,
+    color: Option<String>,
+    log_level: Option<String>,
+    log_file: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Read and parse the config file at `path`.
+    ///
+    /// A warning is printed if the file stores a password but may be read by
+    /// other users.
+    fn read(path: &Path) -> MyResult<Self> {
+        let text = fs::read_to_string(path).map_err(|err| {
+            format!("failed to read config file: {}: {}", path.display(), err)
+        })?;
+        let file: Self = toml::from_str(&text).map_err(|err| {
+            format!("invalid config file: {}: {}", path.display(), err)
+        })?;
+
+        let has_password = file.profiles.values().any(|p| p.password.is_some());
+        let mode = fs::metadata(path)?.permissions().mode();
+        if has_password && mode & 0o077 != 0 {
+            eprintln!(
+                "warning: {} contains a password but can be read by other \
+                 users, consider `chmod 600` or `password_command`",
+                path.display()
+            );
+        }
+        Ok(file)
+    }
+
+    /// Return the profile named `name`, or else the default profile, or an
+    /// empty profile if there is neither.
+    fn profile(mut self, name: Option<String>) -> MyResult<Profile> {
+        match name.or(self.default_profile) {
+            Some(name) => self.profiles.remove(&name).ok_or_else(|| {
+                format!("no such profile in the config file: {}", name).into()
+            }),
+            None => Ok(Profile::default()),
+        }
+    }
+}
+
+/// Return the path of the config file in the user's config directory, i.e.
+/// `$XDG_CONFIG_HOME/chat-boat/client.toml` or
+/// `$HOME/.config/chat-boat/client.toml`.
+fn default_config_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join(CONFIG_FILE))
+}
+
+//==============================================================================
+// Config
+//==============================================================================
+
+/// Where to find the server.
+#[derive(Debug)]
+pub enum ServerAddr {
+    /// A host name or IP address, and a port.
+    Tcp(String, u16),
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+/// How to get the password to log in with.
+#[derive(Debug)]
+pub enum Credentials {
+    /// The password itself.
+    Password(String),
+    /// A shell command that prints the password (a credential helper).
+    Command(String),
+}
+
+impl Credentials {
+    /// Return the password, running the credential helper if needed.
+    ///
+    /// The first line of the helper's output is the password. Its error output
+    /// is shown to the user, so that it may e.g. prompt for a passphrase.
+    pub fn password(&self) -> MyResult<String> {
+        let cmd = match self {
+            Self::Password(pass) => return Ok(pass.clone()),
+            Self::Command(cmd) => cmd,
+        };
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|err| {
+                format!("failed to run password command: {}: {}", cmd, err)
+            })?;
+        if !output.status.success() {
+            return Err(format!(
+                "password command failed ({}): {}",
+                output.status, cmd
+            )
+            .into());
+        }
+
+        let stdout = String::from_utf8(output.stdout).map_err(|_| {
+            format!("password command printed invalid UTF-8: {}", cmd)
+        })?;
+        Ok(stdout.lines().next().unwrap_or_default().to_string())
+    }
+}
+
+/// Client configuration.
+///
+/// Every value is taken from the first of these that sets it:
+/// 1. the command line (see `Args`)
+/// 2. the environment, which includes the `.env` file
+/// 3. the selected profile of the config file
+/// 4. the default
+#[derive(Debug)]
+pub struct Config {
+    /// Where to find the server.
+    pub server: ServerAddr,
+    /// User name to log in as by default (`CHAT_USER`).
+    pub username: Option<String>,
+    /// How to get the password of `username`. Only ever set along with it,
+    /// from the config file, in which case the client logs in on its own.
+    pub credentials: Option<Credentials>,
+    /// When to print colors.
+    pub color: ColorMode,
+    /// Which log messages are printed (`RUST_LOG`, or else `LOG_LEVEL`).
+    pub log_level: LogFilter,
+    /// Path of a file that log messages are appended to instead of being
+    /// printed (`LOG_FILE`).
+    pub log_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Read the configuration from the command line `args`, the environment
+    /// and the config file, in that order of precedence.
+    pub fn load(args: Args) -> MyResult<Self> {
+        let file = match args.config.or(var_opt("CHAT_CLIENT_CONFIG")?) {
+            Some(path) => ConfigFile::read(&path)?,
+            None => match default_config_path() {
+                Some(path) if path.exists() => ConfigFile::read(&path)?,
+                _ => ConfigFile::default(),
+            },
+        };
+        let profile =
+            file.profile(args.profile.or(var_opt("CHAT_PROFILE")?))?;
+
+        let server =
+            Self::server_addr(args.host, args.port, args.socket, &profile)?;
+
+        // Stored credentials only belong to the profile's user name
+        let username =
+            pick(args.username, "CHAT_USER", profile.username.clone())?;
+        let is_profile_user =
+            username.is_some() && username == profile.username;
+        let credentials = match (profile.password, profile.password_command) {
+            (Some(pass), _) => Some(Credentials::Password(pass)),
+            (None, Some(cmd)) => Some(Credentials::Command(cmd)),
+            (None, None) => None,
+        }
+        .filter(|_| is_profile_user);
+
+        let color = args
+            .color
+            .or(file_parse("color", profile.color)?)
+            .unwrap_or(ColorMode::Auto);
+        let log_level = pick(
+            args.log_level,
+            "RUST_LOG",
+            var_opt("LOG_LEVEL")?
+                .or(file_parse("log_level", profile.log_level)?),
+        )?;
+        let log_file = pick(args.log_file, "LOG_FILE", profile.log_file)?;
+
+        Ok(Self {
+            server,
+            username,
+            credentials,
+            color,
+            log_level: match log_level {
+                Some(filter) => filter,
+                None => DEFAULT_LOG_FILTER.parse()?,
+            },
+            log_file,
+        })
+    }
+
+    /// Return where to find the server.
+    ///
+    /// A Unix domain socket is only used if it is given at a higher level of
+    /// precedence than any host or port, e.g. `CHAT_SOCKET` is ignored if
+    /// `--host` is given.
+    fn server_addr(
+        host: Option<String>,
+        port: Option<u16>,
+        socket: Option<PathBuf>,
+        profile: &Profile,
+    ) -> MyResult<ServerAddr> {
+        if let Some(path) = socket {
+            return Ok(ServerAddr::Unix(path));
+        }
+
+        let cli_tcp = host.is_some() || port.is_some();
+        let env_tcp = dotenv::var("CHAT_HOST").is_ok()
+            || dotenv::var("CHAT_PORT").is_ok();
+        if !cli_tcp {
+            if let Some(path) = var_opt("CHAT_SOCKET")? {
+                return Ok(ServerAddr::Unix(path));
+            }
+            if let (false, Some(path)) = (env_tcp, &profile.socket) {
+                return Ok(ServerAddr::Unix(path.clone()));
+            }
+        }
+
+        let host = pick(host, "CHAT_HOST", profile.host.clone())?
+            .unwrap_or_else(|| DEFAULT_HOST.to_string());
+        let port = pick(port, "CHAT_PORT", profile.port)?.unwrap_or(CHAT_PORT);
+        Ok(ServerAddr::Tcp(host, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+default_profile = "work"
+
+[profiles.work]
+host = "file.example"
+port = 1
+username = "alice"
+color = "never"
+
+[profiles.other]
+host = "other.example"
+"#;
+
+    fn load(path: &Path, args: &[&str]) -> Config {
+        let mut argv = vec!["chat-client", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        Config::load(Args::parse_from(argv)).unwrap()
+    }
+
+    fn assert_tcp(config: &Config, host: &str, port: u16) {
+        match &config.server {
+            ServerAddr::Tcp(h, p) => assert_eq!((h.as_str(), *p), (host, port)),
+            other => panic!("expected a TCP address, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_precedence() {
+        let path = env::temp_dir()
+            .join(format!("chat-client-config-{}.toml", process::id()));
+        fs::write(&path, CONFIG).unwrap();
+        for key in [
+            "CHAT_CLIENT_CONFIG",
+            "CHAT_PROFILE",
+            "CHAT_HOST",
+            "CHAT_SOCKET",
+            "CHAT_USER",
+        ] {
+            env::remove_var(key);
+        }
+        env::set_var("CHAT_PORT", "2");
+
+        // Environment over config file, config file over default
+        let config = load(&path, &[]);
+        assert_tcp(&config, "file.example", 2);
+        assert_eq!(config.username.as_deref(), Some("alice"));
+        assert_eq!(config.color, ColorMode::Never);
+
+        // Command line over environment
+        let config = load(&path, &["--host", "cli.example", "--port", "3"]);
+        assert_tcp(&config, "cli.example", 3);
+
+        // A socket in the environment is ignored if the command line sets a
+        // host, and used otherwise
+        env::set_var("CHAT_SOCKET", "/env.sock");
+        let config = load(&path, &["--host", "cli.example"]);
+        assert_tcp(&config, "cli.example", 2);
+        let config = load(&path, &[]);
+        assert!(
+            matches!(config.server, ServerAddr::Unix(p) if p == Path::new("/env.sock"))
+        );
+        env::remove_var("CHAT_SOCKET");
+
+        // Another profile, with defaults for what it does not set
+        let config = load(&path, &["--profile", "other", "--color", "always"]);
+        assert_tcp(&config, "other.example", 2);
+        assert_eq!(config.username, None);
+        assert_eq!(config.color, ColorMode::Always);
+
+        env::remove_var("CHAT_PORT");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,16 +1,35 @@
-use std::process::exit;
+use std::{io, os::unix::io::AsRawFd, process::exit, thread, time::Duration};
 
-use client::TcpClient;
-use tracing::level_filters::STATIC_MAX_LEVEL;
-use tracing_subscriber;
+use clap::Parser;
+use client::ChatClient;
 
-use libchat::{err::MyResult, print_client_banner, CHAT_PORT};
+use libchat::{
+    err::{MyError, MyResult},
+    logging::{init_logging, LogFormat},
+    print_client_banner,
+    sys::{is_tty, SocketCommon},
+};
 
 pub mod client;
 
+mod config;
+use config::{Args, ColorMode, Config, ServerAddr};
+
 pub mod repl;
 use repl::Repl;
 
+mod term;
+
+/// Number of times to try connecting when the server rejects the connection
+/// for a reason that may go away, e.g. because it is full.
+const CONNECT_ATTEMPTS: u32 = 3;
+
+/// Time to wait before trying to connect again.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Time to wait for each address of the server to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn main() {
     if let Err(err) = run() {
         eprintln!("error: {}", err);
@@ -20,14 +39,78 @@ fn main() {
 }
 
 fn run() -> MyResult<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(STATIC_MAX_LEVEL)
-        .init();
+    let config = Config::load(Args::parse())?;
+
+    let color = match config.color {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            is_tty(io::stdout().as_raw_fd()) && dotenv::var("NO_COLOR").is_err()
+        }
+    };
+    colored::control::set_override(color);
+
+    init_logging(
+        &config.log_level,
+        LogFormat::Full,
+        config.log_file.as_deref(),
+        color,
+    )?;
 
     print_client_banner();
 
-    let client = TcpClient::new(CHAT_PORT)?;
-    Repl::new(client).main_loop()?;
+    match &config.server {
+        ServerAddr::Tcp(host, port) => chat(
+            connect(|| ChatClient::connect_tcp(host, *port, CONNECT_TIMEOUT))?,
+            &config,
+        ),
+        ServerAddr::Unix(path) => {
+            chat(connect(|| ChatClient::connect_unix(path))?, &config)
+        }
+    }
+}
 
-    Ok(())
+/// Run the REPL with a client that is connected to the server, logging in
+/// first if the configuration has credentials.
+fn chat<S: SocketCommon>(
+    client: ChatClient<S>,
+    config: &Config,
+) -> MyResult<()> {
+    println!(
+        "Connected to {} (protocol version {}).\n",
+        client.server_name, client.version
+    );
+
+    let mut repl = Repl::new(client, config.username.clone());
+    if let (Some(user), Some(credentials)) =
+        (&config.username, &config.credentials)
+    {
+        repl.login(user, &credentials.password()?)?;
+    }
+    repl.main_loop()
+}
+
+/// Connect to the server with `connect_once`, retrying if the rejection
+/// reason is retryable.
+fn connect<S: SocketCommon>(
+    connect_once: impl Fn() -> MyResult<ChatClient<S>>,
+) -> MyResult<ChatClient<S>> {
+    let mut attempt = 1;
+    loop {
+        match connect_once() {
+            Err(MyError::ClientRejected { reason, message })
+                if reason.is_retryable() && attempt < CONNECT_ATTEMPTS =>
+            {
+                println!(
+                    "Server rejected the connection: {} Retrying in {} \
+                     seconds...",
+                    message,
+                    CONNECT_RETRY_DELAY.as_secs()
+                );
+                thread::sleep(CONNECT_RETRY_DELAY);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
 }
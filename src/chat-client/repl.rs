@@ -1,30 +1,32 @@
 use std::{
-    cell::RefCell,
-    io::{self, Stdin, Stdout, Write},
+    cell::{Cell, RefCell},
+    io::{self, Stdout, Write},
     os::unix::prelude::AsRawFd,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    thread,
-    time::Duration,
 };
 
+use chrono::{Local, TimeZone};
 use colored::{ColoredString, Colorize};
-use libc::POLLIN;
 use regex::Regex;
 use tracing::{info, trace};
 
-use super::client::TcpClient;
+use super::{
+    client::ChatClient,
+    term::{Edit, LineEditor, RawTerm},
+};
 
 use libchat::{
-    err::MyResult, setup_int_handler, sys::poll, PASSWORD_MAX, PASSWORD_MIN,
-    USERNAME_MAX, USERNAME_MIN,
+    check_message, check_password, check_room, check_username,
+    err::{MyError, MyResult},
+    protocol::{Command, Push, Reply, ServerMsg},
+    setup_int_handler,
+    sys::{is_tty, read_fd, wait_readable, SocketCommon},
+    DEFAULT_ROOM, E_NOT_LOGGED_IN, E_NOT_LOGGED_OUT,
 };
 
-static E_NOT_LOGGED_OUT: &str = "Denied. Must be logged out.";
-static E_NOT_LOGGED_IN: &str = "Denied. Please login first.";
-
 macro_rules! _HELP_FORMAT {
     () => {
         "
@@ -35,52 +37,92 @@ Commands always available:
 Commands only available when {} logged in:
 
   newuser USER PASS    Create a new user with the given credentials.
-  login USER PASS      Login to the chat room with the given credentials.
+  login [USER] PASS    Login with the given credentials and join {}.
+                       USER may be left out if a user name was given with
+                       --username or in the config file.
 
 Commands only available when logged in:
 
-  logout               Logout of the chat room and quit Chat Boat.
-  send MSG             Broadcast a message to everyone in the chat room.
+  logout               Logout and quit Chat Boat.
+  send MSG             Broadcast a message to everyone in the current room.
+  join #ROOM           Join a room or switch to it.
+  leave #ROOM          Leave a room.
+  rooms                List the rooms and their number of members.
+  msg USER MSG         Send a private message to a user, which is delivered
+                       when they login if they are offline.
+  who                  List the users who are online and their rooms.
+  history [N]          Show the last N messages sent to your rooms.
 
 "
     };
 }
 
+/// Return `time`, in seconds since the Unix epoch, formatted in local time.
+fn format_time(time: i64) -> String {
+    match Local.timestamp_opt(time, 0).single() {
+        Some(t) => t.format("%Y-%m-%d %H:%M").to_string(),
+        None => "????-??-?? ??:??".to_string(),
+    }
+}
+
 /// Return the commands help message with styalized text.
 fn build_help() -> String {
-    format!(_HELP_FORMAT!(), "not".italic())
+    format!(_HELP_FORMAT!(), "not".italic(), DEFAULT_ROOM)
 }
 
 /// The Client REPL.
 ///
 /// This type manages reading commands in from the user, verifying their syntax,
-/// and sending them to the server via a `TcpClient`.
+/// and sending them to the server via a `ChatClient`. Messages pushed by the
+/// server are printed as they arrive, above the line being typed.
 ///
-/// The only exposed method is `main_loop()` which runs the REPL.
-pub struct Repl {
-    client: TcpClient,
+/// The only exposed method is `main_loop()` which runs the REPL, until the user
+/// quits or the server closes the connection after announcing that it is
+/// shutting down.
+pub struct Repl<S: SocketCommon> {
+    client: ChatClient<S>,
+    /// The user name `login` uses if only a password is given.
+    username: Option<String>,
     logged_in: bool,
-    stdin: Stdin,
+    /// Whether the server announced that it is shutting down, in which case
+    /// the connection being closed is expected.
+    server_closing: Cell<bool>,
+    editor: LineEditor,
+    prompted: Cell<bool>,
     stdout: RefCell<Stdout>,
+    clear_line: &'static str,
     help_msg: String,
     prompt_in_notlogged: ColoredString,
     prompt_in_logged: ColoredString,
     prompt_out_err: ColoredString,
     prompt_out_info: ColoredString,
+    prompt_out_push: ColoredString,
+    prompt_out_dm: ColoredString,
 }
 
-impl Repl {
-    pub fn new(client: TcpClient) -> Self {
+impl<S: SocketCommon> Repl<S> {
+    pub fn new(client: ChatClient<S>, username: Option<String>) -> Self {
         Self {
             client,
+            username,
             logged_in: false,
-            stdin: io::stdin(),
+            server_closing: Cell::new(false),
+            editor: LineEditor::default(),
+            prompted: Cell::new(false),
             stdout: RefCell::new(io::stdout()),
+            // Erase the current line if possible, otherwise just end it
+            clear_line: if is_tty(io::stdout().as_raw_fd()) {
+                "\r\x1b[2K"
+            } else {
+                "\n"
+            },
             help_msg: build_help(),
             prompt_in_notlogged: "< ".bold(),
             prompt_in_logged: "< ".green().bold(),
             prompt_out_err: "> ".red().bold(),
             prompt_out_info: "> ".bright_black(),
+            prompt_out_push: "> ".cyan().bold(),
+            prompt_out_dm: "> ".magenta().bold(),
         }
     }
 
@@ -90,14 +132,33 @@ impl Repl {
 
     /// Print the server reply with the correct prompt and return whether the
     /// reply indicates a success or failure of the previous sent command.
+    ///
+    /// Any pushes received before the reply are printed as well.
     #[inline]
     fn server_reply(&self) -> MyResult<bool> {
-        let reply = self.client.recv_reply()?;
-        match &reply {
-            Ok(msg) => self.print_info(msg)?,
-            Err(msg) => self.print_err(msg)?,
+        self.server_reply_with(|msg| self.print_info(msg))
+    }
+
+    /// Like `server_reply()`, but print a successful reply with `print_ok`
+    /// instead of `print_info()`.
+    fn server_reply_with(
+        &self,
+        print_ok: impl Fn(&str) -> MyResult<()>,
+    ) -> MyResult<bool> {
+        loop {
+            let reply = match self.client.recv_msg()? {
+                ServerMsg::Push(push) => {
+                    self.print_push(&push)?;
+                    continue;
+                }
+                ServerMsg::Reply(reply) => reply,
+            };
+            match &reply {
+                Reply::Ok(msg) => print_ok(msg)?,
+                Reply::Err(msg) => self.print_err(msg)?,
+            }
+            return Ok(reply.is_ok());
         }
-        Ok(reply.is_ok())
     }
 
     //==================================================
@@ -114,6 +175,14 @@ impl Repl {
         }
     }
 
+    /// Print the prompt followed by the line that is currently being typed.
+    fn print_prompt(&self) -> MyResult<()> {
+        self.print(self.get_user_prompt().to_string())?;
+        self.print(self.editor.contents())?;
+        self.prompted.set(true);
+        Ok(())
+    }
+
     /// Print `msg`, ensuring that it appears on the screen even if it contains
     /// no newline by calling `flush()`.
     #[inline]
@@ -129,7 +198,7 @@ impl Repl {
     fn println(&self, msg: impl AsRef<[u8]>) -> MyResult<()> {
         let mut stdout = self.stdout.borrow_mut();
         stdout.write_all(msg.as_ref())?;
-        stdout.write_all(&['\n' as u8])?;
+        stdout.write_all(b"\n")?;
         stdout.flush()?;
         Ok(())
     }
@@ -152,90 +221,237 @@ impl Repl {
         Ok(())
     }
 
+    /// Print `msg` with the direct message prompt.
+    ///
+    /// This is for private messages, both received and sent.
+    #[inline]
+    fn print_dm(&self, msg: impl AsRef<str>) -> MyResult<()> {
+        self.print(self.prompt_out_dm.to_string())?;
+        self.println(msg.as_ref())?;
+        Ok(())
+    }
+
+    /// Print `push` with the server push prompt.
+    ///
+    /// This is for unsolicited messages from the server, e.g. a message sent by
+    /// another user. If the prompt is showing, it is cleared first and re-drawn
+    /// after the message, along with anything the user has typed so far.
+    fn print_push(&self, push: &Push) -> MyResult<()> {
+        // Newer servers may push things this client doesn't know about yet
+        if let Push::Unknown { kind } = push {
+            info!(kind, "ignoring unknown push");
+            return Ok(());
+        }
+
+        let prompted = self.prompted.get();
+        if prompted {
+            self.print(self.clear_line)?;
+        }
+        match push {
+            Push::Message { room, from, text } => {
+                self.print(self.prompt_out_push.to_string())?;
+                self.println(format!("[{}] {}: {}", room, from, text))?;
+            }
+            Push::Direct { from, text } => {
+                self.print_dm(format!("<- {}: {}", from, text))?;
+            }
+            Push::History {
+                time,
+                room,
+                from,
+                text,
+            } => {
+                self.print_info(format!(
+                    "{} [{}] {}: {}",
+                    format_time(*time),
+                    room,
+                    from,
+                    text
+                ))?;
+            }
+            Push::Unread { time, from, text } => {
+                self.print_dm(format!(
+                    "{} <- {}: {}",
+                    format_time(*time),
+                    from,
+                    text
+                ))?;
+            }
+            Push::Login { user } => {
+                self.print_info(format!("{} is online.", user))?;
+            }
+            Push::Logout { user } => {
+                self.print_info(format!("{} went offline.", user))?;
+            }
+            Push::Shutdown { grace, reason } => {
+                let mut msg = "The server is shutting down".to_string();
+                if *grace > 0 {
+                    msg.push_str(&format!(" in {} seconds", grace));
+                }
+                match reason {
+                    Some(reason) => msg.push_str(&format!(": {}", reason)),
+                    None => msg.push('.'),
+                }
+                self.print_err(msg)?;
+                self.server_closing.set(true);
+            }
+            // Returned early above
+            Push::Unknown { .. } => (),
+        }
+        if prompted {
+            self.print_prompt()?;
+        }
+        Ok(())
+    }
+
     //==================================================
     // Main Loop
     //==================================================
 
     /// Run the REPL.
     pub fn main_loop(&mut self) -> MyResult<()> {
-        let stdin = io::stdin();
+        let stdin_fd = io::stdin().as_raw_fd();
+        let term = RawTerm::enable(stdin_fd)?;
 
         let should_stop = Arc::new(AtomicBool::new(false));
-        setup_int_handler(&should_stop)?;
-
-        let mut raw_line = String::new();
-        let re_cmd = Regex::new(r"^\s*(\S+) ?(.*)$")?;
-
-        let delay = Duration::from_millis(25);
+        let sig_pipe = setup_int_handler(&should_stop)?;
 
-        let mut did_prompt = false;
+        let mut input = [0_u8; 256];
 
         loop {
-            thread::sleep(delay);
-
             if should_stop.load(Ordering::Relaxed) {
                 break;
             }
 
-            if !did_prompt {
-                self.print(self.get_user_prompt().to_string())?;
-                did_prompt = true;
+            // Pushes may have been buffered while waiting for a reply, or
+            // together with the reply, so print them before waiting for more.
+            while let Some(msg) = self.client.recv_buffered_msg()? {
+                match msg {
+                    ServerMsg::Push(push) => self.print_push(&push)?,
+                    ServerMsg::Reply(reply) => {
+                        info!(?reply, "received reply with no command");
+                    }
+                }
             }
 
-            if !poll(stdin.as_raw_fd(), POLLIN)? {
-                continue;
+            if !self.prompted.get() {
+                self.print_prompt()?;
             }
 
-            raw_line.clear();
-            self.stdin.read_line(&mut raw_line)?;
-            let line = raw_line.trim_end_matches('\n');
-            did_prompt = false;
-            trace!(line, "input");
-
-            let (cmd, args) = match re_cmd.captures(line) {
-                // If the line matches the command regex, the existance of the 2
-                // match groups is guaranteed.
-                Some(caps) => (
-                    caps.get(1).unwrap().as_str(),
-                    caps.get(2).unwrap().as_str(),
-                ),
-                None => continue,
-            };
+            // Wait on stdin, the server socket and the signal pipe together
+            let sock_fd = self.client.sock.fd();
+            let ready =
+                wait_readable(&[stdin_fd, sock_fd, sig_pipe.fd()], None)?;
 
-            let mut exit = false;
+            if ready.contains(&sig_pipe.fd()) {
+                // The stop flag is checked at the top of the loop
+                sig_pipe.drain()?;
+            }
 
-            let cmd_re = match cmd {
-                "help" => self.print(self.help_msg.clone()),
-                "newuser" => self.cmd_newuser(args),
-                "login" => self.cmd_login(args),
-                "logout" => match self.cmd_logout(args) {
-                    Ok(logout) => {
-                        if logout {
-                            exit = true;
+            if ready.contains(&sock_fd) {
+                match self.client.sock.fill_recv_buf() {
+                    Err(MyError::ConnectionClosed)
+                        if self.server_closing.get() =>
+                    {
+                        if self.prompted.replace(false) {
+                            self.print(self.clear_line)?;
                         }
-                        Ok(())
+                        self.print_info("Disconnected by the server.")?;
+                        break;
                     }
-                    Err(err) => Err(err),
-                },
-                "send" => self.cmd_send(args),
-                _ => self.print_err(format!(
-                    "Error. Command not recognized: {}",
-                    cmd
-                )),
-            };
+                    res => res?,
+                }
+            }
 
-            if let Err(error) = cmd_re {
-                info!(%error, "error while executing command");
+            if !ready.contains(&stdin_fd) {
+                continue;
             }
 
-            if exit {
+            let n_bytes = read_fd(stdin_fd, &mut input)?;
+            if n_bytes == 0 {
+                // End of input
                 break;
             }
+
+            for &byte in &input[..n_bytes] {
+                match self.editor.feed(byte) {
+                    Edit::None => (),
+                    Edit::Insert(b) if term.is_raw() => self.print([b])?,
+                    Edit::Erase if term.is_raw() => self.print("\x08 \x08")?,
+                    Edit::Redraw if term.is_raw() => {
+                        self.print(self.clear_line)?;
+                        self.print_prompt()?;
+                    }
+                    Edit::Insert(_) | Edit::Erase | Edit::Redraw => (),
+                    Edit::Line(line) => {
+                        if term.is_raw() {
+                            self.println("")?;
+                        }
+                        self.prompted.set(false);
+                        if self.run_line(&line)? {
+                            return Ok(());
+                        }
+                        self.print_prompt()?;
+                    }
+                    Edit::Eof => return Ok(()),
+                }
+            }
+        }
+
+        if self.prompted.get() {
+            self.println("")?;
         }
 
         Ok(())
     }
 
+    /// Parse and run one line of input and return whether the REPL should exit.
+    fn run_line(&mut self, line: &str) -> MyResult<bool> {
+        trace!(line, "input");
+
+        let re_cmd = Regex::new(r"^\s*(\S+) ?(.*)$")?;
+        let (cmd, args) = match re_cmd.captures(line) {
+            // If the line matches the command regex, the existance of the 2
+            // match groups is guaranteed.
+            Some(caps) => {
+                (caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str())
+            }
+            None => return Ok(false),
+        };
+
+        let mut exit = false;
+
+        let cmd_re = match cmd {
+            "help" => self.print(self.help_msg.clone()),
+            "newuser" => self.cmd_newuser(args),
+            "login" => self.cmd_login(args),
+            "logout" => match self.cmd_logout(args) {
+                Ok(logout) => {
+                    if logout {
+                        exit = true;
+                    }
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            },
+            "send" => self.cmd_send(args),
+            "join" => self.cmd_join(args),
+            "leave" => self.cmd_leave(args),
+            "rooms" => self.cmd_rooms(args),
+            "msg" => self.cmd_msg(args),
+            "who" => self.cmd_who(args),
+            "history" => self.cmd_history(args),
+            _ => self
+                .print_err(format!("Error. Command not recognized: {}", cmd)),
+        };
+
+        if let Err(error) = cmd_re {
+            info!(%error, "error while executing command");
+        }
+
+        Ok(exit)
+    }
+
     //==================================================
     // Commands
     //==================================================
@@ -260,18 +476,13 @@ impl Repl {
             }
         };
 
-        if user.len() < USERNAME_MIN || user.len() > USERNAME_MAX {
-            self.print_err(format!(
-                "Error. User name must be {}-{} characters",
-                USERNAME_MIN, USERNAME_MAX
-            ))?;
-        } else if pass.len() < PASSWORD_MIN || pass.len() > PASSWORD_MAX {
-            self.print_err(format!(
-                "Error. Password must be {}-{} characters",
-                PASSWORD_MIN, PASSWORD_MAX
-            ))?;
+        if let Err(msg) = check_username(user).and(check_password(pass)) {
+            self.print_err(msg)?;
         } else {
-            self.client.send_cmd(&["newuser", user, pass])?;
+            self.client.send_cmd(&Command::NewUser {
+                user: user.to_string(),
+                pass: pass.to_string(),
+            })?;
             self.server_reply()?;
         }
 
@@ -280,7 +491,7 @@ impl Repl {
 
     /// Parse `args` for the login command and send them to the server.
     ///
-    /// syntax: login USER PASS
+    /// syntax: login [USER] PASS
     ///
     /// This command may only be executed when logged out.
     fn cmd_login(&mut self, args: &str) -> MyResult<()> {
@@ -291,19 +502,33 @@ impl Repl {
 
         let mut a = args.split_ascii_whitespace();
         let (user, pass) = match (a.next(), a.next(), a.next()) {
-            (Some(u), Some(p), None) => (u, p),
+            (Some(u), Some(p), None) => (u.to_string(), p),
+            (Some(p), None, None) if self.username.is_some() => {
+                (self.username.clone().unwrap_or_default(), p)
+            }
             _ => {
-                self.print_err("Error. Syntax: login USER PASS")?;
+                self.print_err("Error. Syntax: login [USER] PASS")?;
                 return Ok(());
             }
         };
 
-        self.client.send_cmd(&["login", user, pass])?;
+        self.login(&user, pass)?;
+        Ok(())
+    }
+
+    /// Log in as `user` with the password `pass`, print the server reply and
+    /// return whether it succeeded.
+    ///
+    /// This is also used to log in on startup, before `main_loop()` is run.
+    pub fn login(&mut self, user: &str, pass: &str) -> MyResult<bool> {
+        self.client.send_cmd(&Command::Login {
+            user: user.to_string(),
+            pass: pass.to_string(),
+        })?;
         if self.server_reply()? {
             self.logged_in = true;
         }
-
-        Ok(())
+        Ok(self.logged_in)
     }
 
     /// Parse `args` for the logout command and send them to the server.
@@ -323,7 +548,7 @@ impl Repl {
         }
         trace!("command LOGOUT");
 
-        self.client.send_cmd(&["logout"])?;
+        self.client.send_cmd(&Command::Logout)?;
         if self.server_reply()? {
             self.logged_in = false;
             Ok(true)
@@ -346,9 +571,168 @@ impl Repl {
             self.print_err("Error. Syntax: send MSG...")?;
             return Ok(());
         }
+        if let Err(msg) = check_message(args) {
+            return self.print_err(msg);
+        }
         trace!(args = ?args, "command SEND");
 
-        self.client.send_cmd(&["send", args])?;
+        self.client.send_cmd(&Command::Send {
+            msg: args.to_string(),
+        })?;
+        self.server_reply()?;
+
+        Ok(())
+    }
+
+    /// Parse `args` for the join command and send them to the server.
+    ///
+    /// syntax: join #ROOM
+    ///
+    /// This command may only be executed when logged in.
+    fn cmd_join(&self, args: &str) -> MyResult<()> {
+        self.cmd_room("join", args, |room| Command::Join { room })
+    }
+
+    /// Parse `args` for the leave command and send them to the server.
+    ///
+    /// syntax: leave #ROOM
+    ///
+    /// This command may only be executed when logged in.
+    fn cmd_leave(&self, args: &str) -> MyResult<()> {
+        self.cmd_room("leave", args, |room| Command::Leave { room })
+    }
+
+    /// Parse `args` for a command named `name` that takes one room name, and
+    /// send the command built by `cmd` to the server.
+    fn cmd_room(
+        &self,
+        name: &str,
+        args: &str,
+        cmd: impl FnOnce(String) -> Command,
+    ) -> MyResult<()> {
+        if !self.logged_in {
+            return self.print_err(E_NOT_LOGGED_IN);
+        }
+        trace!(args = ?args, "command {}", name.to_uppercase());
+
+        let mut a = args.split_ascii_whitespace();
+        let room = match (a.next(), a.next()) {
+            (Some(r), None) => r,
+            _ => {
+                self.print_err(format!("Error. Syntax: {} #ROOM", name))?;
+                return Ok(());
+            }
+        };
+        if let Err(msg) = check_room(room) {
+            return self.print_err(msg);
+        }
+
+        self.client.send_cmd(&cmd(room.to_string()))?;
+        self.server_reply()?;
+
+        Ok(())
+    }
+
+    /// Parse `args` for the rooms command and send them to the server.
+    ///
+    /// syntax: rooms
+    ///
+    /// This command may only be executed when logged in.
+    fn cmd_rooms(&self, args: &str) -> MyResult<()> {
+        if !self.logged_in {
+            return self.print_err(E_NOT_LOGGED_IN);
+        }
+
+        if !args.chars().all(|c| c.is_ascii_whitespace()) {
+            self.print_err("Error. Syntax: rooms")?;
+            return Ok(());
+        }
+        trace!("command ROOMS");
+
+        self.client.send_cmd(&Command::Rooms)?;
+        self.server_reply()?;
+
+        Ok(())
+    }
+
+    /// Parse `args` for the msg command and send them to the server.
+    ///
+    /// syntax: msg USER MSG...
+    ///
+    /// This command may only be executed when logged in.
+    fn cmd_msg(&self, args: &str) -> MyResult<()> {
+        if !self.logged_in {
+            return self.print_err(E_NOT_LOGGED_IN);
+        }
+
+        let re_args = Regex::new(r"^\s*(\S+)\s+(.*\S.*)$")?;
+        let (to, text) = match re_args.captures(args) {
+            Some(caps) => {
+                (caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str())
+            }
+            None => {
+                self.print_err("Error. Syntax: msg USER MSG...")?;
+                return Ok(());
+            }
+        };
+        if let Err(msg) = check_username(to).and(check_message(text)) {
+            return self.print_err(msg);
+        }
+        trace!(to, text, "command MSG");
+
+        self.client.send_cmd(&Command::Msg {
+            to: to.to_string(),
+            text: text.to_string(),
+        })?;
+        self.server_reply_with(|msg| self.print_dm(msg))?;
+
+        Ok(())
+    }
+
+    /// Send the who command to the server.
+    ///
+    /// syntax: who
+    ///
+    /// This command may only be executed when logged in.
+    fn cmd_who(&self, args: &str) -> MyResult<()> {
+        if !self.logged_in {
+            return self.print_err(E_NOT_LOGGED_IN);
+        }
+
+        if !args.chars().all(|c| c.is_ascii_whitespace()) {
+            self.print_err("Error. Syntax: who")?;
+            return Ok(());
+        }
+        trace!("command WHO");
+
+        self.client.send_cmd(&Command::Who)?;
+        self.server_reply()?;
+
+        Ok(())
+    }
+
+    /// Parse `args` for the history command and send them to the server.
+    ///
+    /// syntax: history [N]
+    ///
+    /// This command may only be executed when logged in.
+    fn cmd_history(&self, args: &str) -> MyResult<()> {
+        if !self.logged_in {
+            return self.print_err(E_NOT_LOGGED_IN);
+        }
+        trace!(args = ?args, "command HISTORY");
+
+        let mut a = args.split_ascii_whitespace();
+        let count = match (a.next().map(str::parse), a.next()) {
+            (None, None) => None,
+            (Some(Ok(n)), None) => Some(n),
+            _ => {
+                self.print_err("Error. Syntax: history [N]")?;
+                return Ok(());
+            }
+        };
+
+        self.client.send_cmd(&Command::History { count })?;
         self.server_reply()?;
 
         Ok(())
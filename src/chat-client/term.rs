@@ -0,0 +1,143 @@
+use std::{io, mem::MaybeUninit};
+
+use libc::{
+    c_int, tcgetattr, tcsetattr, termios, ECHO, ICANON, TCSANOW, VMIN, VTIME,
+};
+use tracing::debug;
+
+use libchat::{err::MyResult, sys::is_tty};
+
+/// Guard that puts a terminal into non-canonical mode with echo disabled.
+///
+/// This lets the REPL see every key press as it happens and keep its own copy
+/// of the line being typed, so that it can be re-drawn after printing a message
+/// from the server. Signal generation is left enabled so ^C still works.
+///
+/// The original terminal settings are restored when the guard is dropped. If
+/// the file descriptor is not a terminal then the guard does nothing.
+pub struct RawTerm {
+    fd: c_int,
+    orig: Option<termios>,
+}
+
+impl Drop for RawTerm {
+    fn drop(&mut self) {
+        if let Some(orig) = &self.orig {
+            debug!(fd = self.fd, "restoring terminal mode");
+            unsafe {
+                tcsetattr(self.fd, TCSANOW, orig);
+            }
+        }
+    }
+}
+
+impl RawTerm {
+    /// Put the terminal `fd` into non-canonical mode with echo disabled.
+    pub fn enable(fd: c_int) -> MyResult<Self> {
+        if !is_tty(fd) {
+            return Ok(Self { fd, orig: None });
+        }
+
+        let mut orig = MaybeUninit::<termios>::uninit();
+        if unsafe { tcgetattr(fd, orig.as_mut_ptr()) } < 0 {
+            let err = io::Error::last_os_error();
+            return Err(format!("failed to get terminal mode: {}", err).into());
+        }
+        let orig = unsafe { orig.assume_init() };
+
+        let mut raw = orig;
+        raw.c_lflag &= !(ICANON | ECHO);
+        // Block until at least one byte is available, with no timeout
+        raw.c_cc[VMIN] = 1;
+        raw.c_cc[VTIME] = 0;
+
+        if unsafe { tcsetattr(fd, TCSANOW, &raw) } < 0 {
+            let err = io::Error::last_os_error();
+            return Err(format!("failed to set terminal mode: {}", err).into());
+        }
+        debug!(fd, "enabled raw terminal mode");
+
+        Ok(Self {
+            fd,
+            orig: Some(orig),
+        })
+    }
+
+    /// Return whether the terminal is in raw mode, i.e. whether input must be
+    /// echoed by the application.
+    #[inline]
+    pub fn is_raw(&self) -> bool {
+        self.orig.is_some()
+    }
+}
+
+/// The result of feeding one byte of input to a `LineEditor`.
+pub enum Edit {
+    /// Nothing to do.
+    None,
+    /// A byte was appended to the line and should be echoed.
+    Insert(u8),
+    /// The last character of the line was removed.
+    Erase,
+    /// The line changed and should be re-drawn completely.
+    Redraw,
+    /// A line was completed.
+    Line(String),
+    /// End of input was requested (^D on an empty line).
+    Eof,
+}
+
+/// A minimal line editor for a terminal in raw mode.
+///
+/// Bytes are fed one at a time and the resulting edit is returned so the
+/// caller can update the screen. The current contents of the line are
+/// available at any time for re-drawing.
+#[derive(Default)]
+pub struct LineEditor {
+    buf: Vec<u8>,
+}
+
+impl LineEditor {
+    /// Return the line being edited.
+    #[inline]
+    pub fn contents(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Process one byte of input.
+    pub fn feed(&mut self, byte: u8) -> Edit {
+        match byte {
+            b'\n' | b'\r' => {
+                let line = String::from_utf8_lossy(&self.buf).to_string();
+                self.buf.clear();
+                Edit::Line(line)
+            }
+            // Backspace or delete: remove one (possibly multi-byte) character
+            0x08 | 0x7f => {
+                while let Some(b) = self.buf.pop() {
+                    // Stop at the first byte that is not a UTF-8 continuation
+                    if b & 0xc0 != 0x80 {
+                        return Edit::Erase;
+                    }
+                }
+                Edit::None
+            }
+            // ^U: kill the whole line
+            0x15 => {
+                self.buf.clear();
+                Edit::Redraw
+            }
+            // ^D: end of input, but only if the line is empty
+            0x04 if self.buf.is_empty() => Edit::Eof,
+            b'\t' => {
+                self.buf.push(byte);
+                Edit::Insert(byte)
+            }
+            b if b < 0x20 => Edit::None,
+            b => {
+                self.buf.push(b);
+                Edit::Insert(b)
+            }
+        }
+    }
+}
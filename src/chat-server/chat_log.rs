@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use libchat::err::MyResult;
+
+use super::history::Entry;
+
+/// A chat event recorded in the chat log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    /// A message was sent to a room.
+    Message {
+        room: String,
+        from: String,
+        text: String,
+    },
+    /// A user joined a room, including the default room on login.
+    Join { room: String, user: String },
+    /// A user left a room, including every room on logout or disconnect.
+    Leave { room: String, user: String },
+    /// A private message was sent. Only logged if `CHAT_LOG_DMS` is set.
+    Direct {
+        from: String,
+        to: String,
+        text: String,
+    },
+}
+
+/// One line of the chat log: an event and when it happened, in seconds since
+/// the Unix epoch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub time: i64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Record {
+    /// Create a record for an event that happened now.
+    pub fn now(event: Event) -> Self {
+        Self {
+            time: Utc::now().timestamp(),
+            event,
+        }
+    }
+
+    /// Return the history entry for this record if it is a room message.
+    pub fn to_entry(&self) -> Option<Entry> {
+        match &self.event {
+            Event::Message { room, from, text } => Some(Entry {
+                time: self.time,
+                room: room.clone(),
+                from: from.clone(),
+                text: text.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<&Entry> for Record {
+    fn from(entry: &Entry) -> Self {
+        Self {
+            time: entry.time,
+            event: Event::Message {
+                room: entry.room.clone(),
+                from: entry.from.clone(),
+                text: entry.text.clone(),
+            },
+        }
+    }
+}
+
+/// An append-only log of chat events, rotated by size.
+///
+/// The log is a JSON Lines file: every line is one JSON object with a `time`
+/// (seconds since the Unix epoch), an `event` naming the kind of event, and
+/// the fields of that event, e.g.
+///
+/// ```text
+/// {"time":1700000000,"event":"join","room":"#general","user":"alice"}
+/// {"time":1700000005,"event":"message","room":"#general","from":"alice","text":"hi"}
+/// {"time":1700000009,"event":"direct","from":"alice","to":"bob","text":"psst"}
+/// {"time":1700000012,"event":"leave","room":"#general","user":"alice"}
+/// ```
+///
+/// Before a line would make the file larger than the maximum size, the file
+/// is rotated: `<path>` is renamed to `<path>.1`, `<path>.1` to `<path>.2` and
+/// so on, dropping the oldest file once there are `keep` rotated files.
+#[derive(Debug)]
+pub struct ChatLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl ChatLog {
+    /// Open the chat log at `path` for appending, creating it if needed.
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_size: u64,
+        keep: usize,
+    ) -> MyResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::open_file(&path)?;
+        let size = file.metadata()?.len();
+        debug!(path = %path.display(), size, "opened chat log");
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    /// Append `record` as one line, rotating the log first if needed.
+    pub fn append(&mut self, record: &Record) -> MyResult<()> {
+        let mut line = serde_json::to_string(record)
+            .map_err(|e| format!("failed to encode chat log record: {}", e))?;
+        line.push('\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes()).map_err(|err| {
+            format!(
+                "failed to write chat log file: {}: {}",
+                self.path.display(),
+                err
+            )
+        })?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the current file to disk.
+    ///
+    /// Records are not synced as they are appended, so that a busy chat is not
+    /// slowed down by the disk, so this should be called before exiting.
+    pub fn flush(&mut self) -> MyResult<()> {
+        self.file.sync_data().map_err(|err| {
+            format!(
+                "failed to flush chat log file: {}: {}",
+                self.path.display(),
+                err
+            )
+            .into()
+        })
+    }
+
+    /// Return the last `n` room messages in the log, oldest first.
+    ///
+    /// The current file is read first and then the rotated files from newest
+    /// to oldest, stopping as soon as `n` messages were found, and only the
+    /// messages that are returned are kept in memory.
+    pub fn recent_messages(&self, n: usize) -> MyResult<Vec<Entry>> {
+        // The messages of each file read, newest file first
+        let mut files = Vec::new();
+        let mut n_found = 0;
+        for file_no in 0..=self.keep {
+            if n_found >= n {
+                break;
+            }
+            let wanted = n - n_found;
+            let mut entries = VecDeque::new();
+            self.read_file(file_no, |record| {
+                if let Some(entry) = record.to_entry() {
+                    if entries.len() == wanted {
+                        entries.pop_front();
+                    }
+                    entries.push_back(entry);
+                }
+            })?;
+            n_found += entries.len();
+            files.push(entries);
+        }
+        Ok(files.into_iter().rev().flatten().collect())
+    }
+
+    /// Call `f` with every record of the `n`th rotated file (see
+    /// `rotated_path()`) in order. A file that doesn't exist has no records.
+    ///
+    /// Lines that can't be decoded are skipped, so that a line cut short by a
+    /// crash doesn't prevent the server from starting.
+    fn read_file(&self, n: usize, mut f: impl FnMut(Record)) -> MyResult<()> {
+        let path = self.rotated_path(n);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => return Ok(()),
+        };
+        for (line_no, line) in BufReader::new(file).lines().enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(record) => f(record),
+                Err(error) => info!(
+                    path = %path.display(),
+                    line_no,
+                    %error,
+                    "skipping invalid chat log line"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Shift the rotated files up by one and start a new, empty current file.
+    fn rotate(&mut self) -> MyResult<()> {
+        debug!(path = %self.path.display(), size = self.size, "rotating chat log");
+
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // Renaming over the last file drops it
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = Self::open_file(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Return the path of the `n`th rotated file, where 0 is the current file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        if n == 0 {
+            return self.path.clone();
+        }
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn open_file(path: &Path) -> MyResult<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                format!(
+                    "failed to open chat log file: {}: {}",
+                    path.display(),
+                    err
+                )
+                .into()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Return an empty directory for the test called `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "chat-log-test-{}-{}",
+            process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn message(n: i64) -> Record {
+        Record {
+            time: n,
+            event: Event::Message {
+                room: "#general".to_string(),
+                from: "alice".to_string(),
+                text: n.to_string(),
+            },
+        }
+    }
+
+    fn texts(entries: &[Entry]) -> Vec<String> {
+        entries.iter().map(|e| e.text.clone()).collect()
+    }
+
+    #[test]
+    fn rotation_keeps_newest_files() {
+        let dir = test_dir("rotation");
+        let path = dir.join("chat.log");
+        let line_len = serde_json::to_string(&message(0)).unwrap().len() + 1;
+
+        // Room for two records per file
+        let mut log = ChatLog::open(&path, 2 * line_len as u64, 2).unwrap();
+        for n in 0..9 {
+            log.append(&message(n)).unwrap();
+            log.append(&Record {
+                time: n,
+                event: Event::Join {
+                    room: "#general".to_string(),
+                    user: "bob".to_string(),
+                },
+            })
+            .unwrap();
+        }
+
+        let mut files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["chat.log", "chat.log.1", "chat.log.2"]);
+
+        // Every file holds one message, and joins are not messages
+        let all = log.recent_messages(usize::MAX).unwrap();
+        assert_eq!(texts(&all), ["6", "7", "8"]);
+        let recent = log.recent_messages(2).unwrap();
+        assert_eq!(texts(&recent), ["7", "8"]);
+        assert!(log.recent_messages(0).unwrap().is_empty());
+
+        // Reopening appends to the current file
+        drop(log);
+        let log = ChatLog::open(&path, 2 * line_len as u64, 2).unwrap();
+        assert_eq!(texts(&log.recent_messages(10).unwrap()), ["6", "7", "8"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_without_keep_truncates() {
+        let dir = test_dir("no-keep");
+        let path = dir.join("chat.log");
+        let line_len = serde_json::to_string(&message(0)).unwrap().len() + 1;
+
+        let mut log = ChatLog::open(&path, 2 * line_len as u64, 0).unwrap();
+        for n in 0..5 {
+            log.append(&message(n)).unwrap();
+        }
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(texts(&log.recent_messages(10).unwrap()), ["4"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_lines_are_skipped() {
+        let dir = test_dir("invalid");
+        let path = dir.join("chat.log");
+        let mut log = ChatLog::open(&path, u64::MAX, 1).unwrap();
+        log.append(&message(1)).unwrap();
+        log.file.write_all(b"{\"time\":2,\"ev\n").unwrap();
+        log.append(&message(3)).unwrap();
+
+        assert_eq!(texts(&log.recent_messages(10).unwrap()), ["1", "3"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
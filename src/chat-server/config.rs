@@ -0,0 +1,439 @@
+use std::{
+    fmt::Display,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize, Serializer};
+
+use libchat::{
+    config::{file_parse, pick, var_opt},
+    err::MyResult,
+    logging::{LogFilter, LogFormat},
+    UserStoreKind, CHAT_PORT,
+};
+
+/// The address to listen on if `CHAT_HOST` is not set.
+const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// The name the server introduces itself with if `SERVER_NAME` is not set.
+const DEFAULT_SERVER_NAME: &str = "Chat Boat";
+
+/// The maximum number of clients if `MAX_CLIENTS` is not set.
+const DEFAULT_MAX_CLIENTS: usize = 64;
+
+/// The number of messages kept in the history if `HISTORY_SIZE` is not set.
+const DEFAULT_HISTORY_SIZE: usize = 100;
+
+/// The number of messages replayed after login if `HISTORY_REPLAY` is not set.
+const DEFAULT_HISTORY_REPLAY: usize = 10;
+
+/// The size in bytes at which the chat log is rotated if `CHAT_LOG_MAX_SIZE`
+/// is not set.
+const DEFAULT_CHAT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The number of rotated chat log files kept if `CHAT_LOG_KEEP` is not set.
+const DEFAULT_CHAT_LOG_KEEP: usize = 5;
+
+/// The directory of the offline message mailboxes if `MAILBOX_DIR` is not set.
+const DEFAULT_MAILBOX_DIR: &str = "mailbox";
+
+/// The number of seconds clients are given to finish up when the server shuts
+/// down if `SHUTDOWN_GRACE` is not set.
+const DEFAULT_SHUTDOWN_GRACE: u32 = 0;
+
+/// The log filter if neither `RUST_LOG` nor `LOG_LEVEL` is set.
+const DEFAULT_LOG_FILTER: &str = "info";
+
+//==============================================================================
+// Command line
+//==============================================================================
+
+/// Run the Chat Boat chat server.
+///
+/// Every option can also be set in the environment (which includes the `.env`
+/// file) or in the config file. The command line takes precedence over the
+/// environment, which takes precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Read settings from this TOML file [env: CHAT_CONFIG]
+    #[arg(long, short, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Print the configuration that would be used as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Address to listen on, IPv4 or IPv6 [env: CHAT_HOST]
+    /// [default: 127.0.0.1]
+    #[arg(long)]
+    pub host: Option<IpAddr>,
+
+    /// Port to listen on [env: CHAT_PORT] [default: 10087]
+    #[arg(long, short)]
+    pub port: Option<u16>,
+
+    /// Listen on the Unix domain socket at this path instead of a TCP port
+    /// [env: CHAT_SOCKET]
+    #[arg(long, value_name = "PATH")]
+    pub socket: Option<PathBuf>,
+
+    /// Name the server introduces itself with [env: SERVER_NAME]
+    /// [default: Chat Boat]
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Kind of users database, `text` or `sqlite` [env: USERS_BACKEND]
+    /// [default: text]
+    #[arg(long, value_name = "KIND")]
+    pub users_backend: Option<UserStoreKind>,
+
+    /// Path of the users database file [env: USERS_DB]
+    #[arg(long, value_name = "PATH")]
+    pub users_db: Option<PathBuf>,
+
+    /// Maximum number of connected clients [env: MAX_CLIENTS] [default: 64]
+    #[arg(long, value_name = "N")]
+    pub max_clients: Option<usize>,
+
+    /// Number of recent messages kept for the `history` command
+    /// [env: HISTORY_SIZE] [default: 100]
+    #[arg(long, value_name = "N")]
+    pub history_size: Option<usize>,
+
+    /// Which log messages to print, either a level (`off`, `error`, `warn`,
+    /// `info`, `debug` or `trace`) or directives like `RUST_LOG`, e.g.
+    /// `warn,chat_server=debug` [env: RUST_LOG, LOG_LEVEL] [default: info]
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<LogFilter>,
+
+    /// Log format, one of `full`, `compact`, `pretty` or `json`
+    /// [env: LOG_FORMAT] [default: full]
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Append log messages to this file instead of printing them
+    /// [env: LOG_FILE]
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Message of the day, shown to every user after logging in [env: MOTD]
+    #[arg(long, value_name = "TEXT")]
+    pub motd: Option<String>,
+
+    /// Seconds between telling clients that the server is shutting down and
+    /// disconnecting them [env: SHUTDOWN_GRACE] [default: 0]
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_grace: Option<u32>,
+
+    /// Reason shown to clients when the server shuts down
+    /// [env: SHUTDOWN_REASON]
+    #[arg(long, value_name = "TEXT")]
+    pub shutdown_reason: Option<String>,
+}
+
+//==============================================================================
+// Config file
+//==============================================================================
+
+/// The contents of the config file.
+///
+/// Every key is optional and named like the field of `Config` it sets. Values
+/// that are parsed from text, like the users backend or the log level, are
+/// kept as strings until they are parsed along with the environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    host: Option<IpAddr>,
+    port: Option<u16>,
+    socket: Option<PathBuf>,
+    name: Option<String>,
+    users_backend: Option<String>,
+    users_db: Option<PathBuf>,
+    max_clients: Option<usize>,
+    banned: Option<Vec<IpAddr>>,
+    history_size: Option<usize>,
+    history_replay: Option<usize>,
+    chat_log: Option<PathBuf>,
+    chat_log_max_size: Option<u64>,
+    chat_log_keep: Option<usize>,
+    chat_log_dms: Option<bool>,
+    mailbox_dir: Option<PathBuf>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+    log_file: Option<PathBuf>,
+    motd: Option<String>,
+    shutdown_grace: Option<u32>,
+    shutdown_reason: Option<String>,
+}
+
+impl ConfigFile {
+    /// Read and parse the config file at `path`.
+    fn read(path: &Path) -> MyResult<Self> {
+        let text = fs::read_to_string(path).map_err(|err| {
+            format!("failed to read config file: {}: {}", path.display(), err)
+        })?;
+        Ok(toml::from_str(&text).map_err(|err| {
+            format!("invalid config file: {}: {}", path.display(), err)
+        })?)
+    }
+}
+
+//==============================================================================
+// Config
+//==============================================================================
+
+/// Server configuration.
+///
+/// Every value is taken from the first of these that sets it:
+/// 1. the command line (see `Args`)
+/// 2. the environment, which includes the `.env` file
+/// 3. the config file, if one is given with `--config` or `CHAT_CONFIG`
+/// 4. the default
+#[derive(Debug, Serialize)]
+pub struct Config {
+    /// Address to listen on (`CHAT_HOST`), either IPv4 or IPv6. `0.0.0.0` or
+    /// `::` listens on every local address.
+    pub host: IpAddr,
+    /// Port to listen on (`CHAT_PORT`).
+    pub port: u16,
+    /// Path of a Unix domain socket to listen on instead of `host` and `port`
+    /// (`CHAT_SOCKET`).
+    pub socket: Option<PathBuf>,
+    /// Name the server introduces itself with during the handshake.
+    pub name: String,
+    /// Kind of users database (`USERS_BACKEND`, either `text` or `sqlite`).
+    #[serde(serialize_with = "serialize_display")]
+    pub users_backend: UserStoreKind,
+    /// Path of the users database file (`USERS_DB`). There is no default.
+    pub users_db: PathBuf,
+    /// Maximum number of clients that may be connected at once
+    /// (`MAX_CLIENTS`).
+    pub max_clients: usize,
+    /// Addresses that are not allowed to connect (`BANNED_ADDRS`, a comma
+    /// separated list).
+    pub banned: Vec<IpAddr>,
+    /// Maximum number of recent messages kept for the `history` command
+    /// (`HISTORY_SIZE`).
+    pub history_size: usize,
+    /// Number of recent messages replayed to a client right after it logs in
+    /// (`HISTORY_REPLAY`). Zero disables the replay.
+    pub history_replay: usize,
+    /// Path of the chat log file (`CHAT_LOG`). If not set, chat events are
+    /// not logged and the history starts out empty.
+    pub chat_log: Option<PathBuf>,
+    /// Size in bytes at which the chat log is rotated (`CHAT_LOG_MAX_SIZE`).
+    pub chat_log_max_size: u64,
+    /// Number of rotated chat log files to keep (`CHAT_LOG_KEEP`).
+    pub chat_log_keep: usize,
+    /// Whether private messages are written to the chat log (`CHAT_LOG_DMS`,
+    /// either `true` or `false`).
+    pub chat_log_dms: bool,
+    /// Directory where private messages to offline users are kept until they
+    /// log in (`MAILBOX_DIR`).
+    pub mailbox_dir: PathBuf,
+    /// Which log messages are printed (`RUST_LOG`, or else `LOG_LEVEL`).
+    pub log_level: LogFilter,
+    /// How log messages are formatted (`LOG_FORMAT`).
+    pub log_format: LogFormat,
+    /// Path of a file that log messages are appended to instead of being
+    /// printed (`LOG_FILE`).
+    pub log_file: Option<PathBuf>,
+    /// Message of the day, shown to every user after logging in (`MOTD`).
+    pub motd: Option<String>,
+    /// Number of seconds between telling clients that the server is shutting
+    /// down and disconnecting them (`SHUTDOWN_GRACE`). The server exits
+    /// earlier if every client leaves.
+    pub shutdown_grace: u32,
+    /// Reason given to clients when the server shuts down
+    /// (`SHUTDOWN_REASON`).
+    pub shutdown_reason: Option<String>,
+}
+
+impl Config {
+    /// Read the configuration from the command line `args`, the environment
+    /// and the config file, in that order of precedence.
+    pub fn load(args: Args) -> MyResult<Self> {
+        let file = match args.config.or(var_opt("CHAT_CONFIG")?) {
+            Some(path) => ConfigFile::read(&path)?,
+            None => ConfigFile::default(),
+        };
+
+        let host = pick(args.host, "CHAT_HOST", file.host)?;
+        let port = pick(args.port, "CHAT_PORT", file.port)?;
+        let socket = pick(args.socket, "CHAT_SOCKET", file.socket)?;
+        let name = pick(args.name, "SERVER_NAME", file.name)?;
+
+        let users_backend = pick(
+            args.users_backend,
+            "USERS_BACKEND",
+            file_parse("users_backend", file.users_backend)?,
+        )?;
+        let users_db = pick(args.users_db, "USERS_DB", file.users_db)?
+            .ok_or_else(|| {
+                "no users database given, use --users-db, USERS_DB or \
+                 users_db in the config file"
+                    .to_string()
+            })?;
+
+        let max_clients =
+            pick(args.max_clients, "MAX_CLIENTS", file.max_clients)?;
+        let history_size =
+            pick(args.history_size, "HISTORY_SIZE", file.history_size)?;
+        let history_replay = pick(None, "HISTORY_REPLAY", file.history_replay)?;
+
+        let chat_log = pick(None, "CHAT_LOG", file.chat_log)?;
+        let chat_log_max_size =
+            pick(None, "CHAT_LOG_MAX_SIZE", file.chat_log_max_size)?;
+        let chat_log_keep = pick(None, "CHAT_LOG_KEEP", file.chat_log_keep)?;
+        let chat_log_dms = pick(None, "CHAT_LOG_DMS", file.chat_log_dms)?;
+
+        let mailbox_dir = pick(None, "MAILBOX_DIR", file.mailbox_dir)?;
+
+        let banned = match dotenv::var("BANNED_ADDRS") {
+            Ok(addrs) => addrs
+                .split(',')
+                .map(str::trim)
+                .filter(|a| !a.is_empty())
+                .map(|a| {
+                    a.parse().map_err(|_| {
+                        format!(
+                            "BANNED_ADDRS contains an invalid address: {}",
+                            a
+                        )
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            Err(_) => file.banned.unwrap_or_default(),
+        };
+
+        let log_level = pick(
+            args.log_level,
+            "RUST_LOG",
+            var_opt("LOG_LEVEL")?.or(file_parse("log_level", file.log_level)?),
+        )?;
+        let log_format = pick(
+            args.log_format,
+            "LOG_FORMAT",
+            file_parse("log_format", file.log_format)?,
+        )?;
+        let log_file = pick(args.log_file, "LOG_FILE", file.log_file)?;
+        let motd = pick(args.motd, "MOTD", file.motd)?;
+        let shutdown_grace =
+            pick(args.shutdown_grace, "SHUTDOWN_GRACE", file.shutdown_grace)?;
+        let shutdown_reason = pick(
+            args.shutdown_reason,
+            "SHUTDOWN_REASON",
+            file.shutdown_reason,
+        )?;
+
+        Ok(Self {
+            host: host.unwrap_or(DEFAULT_HOST),
+            port: port.unwrap_or(CHAT_PORT),
+            socket,
+            name: name.unwrap_or_else(|| DEFAULT_SERVER_NAME.to_string()),
+            users_backend: users_backend.unwrap_or(UserStoreKind::Text),
+            users_db,
+            max_clients: max_clients.unwrap_or(DEFAULT_MAX_CLIENTS),
+            banned,
+            history_size: history_size.unwrap_or(DEFAULT_HISTORY_SIZE),
+            history_replay: history_replay.unwrap_or(DEFAULT_HISTORY_REPLAY),
+            chat_log,
+            chat_log_max_size: chat_log_max_size
+                .unwrap_or(DEFAULT_CHAT_LOG_MAX_SIZE),
+            chat_log_keep: chat_log_keep.unwrap_or(DEFAULT_CHAT_LOG_KEEP),
+            chat_log_dms: chat_log_dms.unwrap_or(false),
+            mailbox_dir: mailbox_dir
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_MAILBOX_DIR)),
+            log_level: match log_level {
+                Some(filter) => filter,
+                None => DEFAULT_LOG_FILTER.parse()?,
+            },
+            log_format: log_format.unwrap_or(LogFormat::Full),
+            log_file,
+            motd,
+            shutdown_grace: shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE),
+            shutdown_reason,
+        })
+    }
+
+    /// Return the configuration as a TOML document that can be used as a
+    /// config file.
+    pub fn to_toml(&self) -> MyResult<String> {
+        Ok(toml::to_string(self)
+            .map_err(|e| format!("failed to encode config: {}", e))?)
+    }
+}
+
+/// Serialize a value as the string it displays as.
+fn serialize_display<T: Display, S: Serializer>(
+    val: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn load_precedence() {
+        let path = env::temp_dir()
+            .join(format!("chat-server-config-{}.toml", process::id()));
+        fs::write(
+            &path,
+            "port = 1\nname = \"file\"\nmax_clients = 3\nmotd = \"file\"\n",
+        )
+        .unwrap();
+        for key in [
+            "CHAT_CONFIG",
+            "CHAT_HOST",
+            "MAX_CLIENTS",
+            "HISTORY_SIZE",
+            "MOTD",
+        ] {
+            env::remove_var(key);
+        }
+        env::set_var("CHAT_PORT", "2");
+        env::set_var("SERVER_NAME", "env");
+        env::set_var("SHUTDOWN_GRACE", "not a number");
+
+        let args = Args::parse_from([
+            "chat-server",
+            "--config",
+            path.to_str().unwrap(),
+            "--port",
+            "3",
+            "--users-db",
+            "cli.db",
+            "--shutdown-grace",
+            "4",
+        ]);
+        let config = Config::load(args);
+        for key in ["CHAT_PORT", "SERVER_NAME", "SHUTDOWN_GRACE"] {
+            env::remove_var(key);
+        }
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        // Command line over environment over config file
+        assert_eq!(config.port, 3);
+        assert_eq!(config.users_db, PathBuf::from("cli.db"));
+        // The environment is not even parsed if the command line sets a value
+        assert_eq!(config.shutdown_grace, 4);
+        // Environment over config file
+        assert_eq!(config.name, "env");
+        // Config file over default
+        assert_eq!(config.max_clients, 3);
+        assert_eq!(config.motd.as_deref(), Some("file"));
+        // Default
+        assert_eq!(config.history_size, DEFAULT_HISTORY_SIZE);
+        assert_eq!(config.host, DEFAULT_HOST);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+use libchat::protocol::Push;
+
+/// A message that was sent to a room.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// When the message was sent, in seconds since the Unix epoch.
+    pub time: i64,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+}
+
+impl Entry {
+    /// Create an entry for a message sent now.
+    pub fn now(room: &str, from: &str, text: &str) -> Self {
+        Self {
+            time: Utc::now().timestamp(),
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Return the push that replays this entry to a client.
+    pub fn to_push(&self) -> Push {
+        Push::History {
+            time: self.time,
+            room: self.room.clone(),
+            from: self.from.clone(),
+            text: self.text.clone(),
+        }
+    }
+}
+
+/// A bounded buffer of the most recent messages sent to any room.
+///
+/// Once the buffer is full, adding a message drops the oldest one.
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    /// Create an empty history holding at most `capacity` messages.
+    ///
+    /// Nothing is allocated up front, since `capacity` is configurable and
+    /// may be far larger than the number of messages ever sent.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Add `entry` as the newest message, dropping the oldest message if the
+    /// history is full.
+    pub fn push(&mut self, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Add every entry of `entries` in order. See `push()`.
+    pub fn extend(&mut self, entries: impl IntoIterator<Item = Entry>) {
+        for entry in entries {
+            self.push(entry);
+        }
+    }
+
+    /// Return the last `n` messages for which `pred` returns true, oldest
+    /// first.
+    pub fn recent(
+        &self,
+        n: usize,
+        pred: impl Fn(&Entry) -> bool,
+    ) -> Vec<&Entry> {
+        let mut recent = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| pred(e))
+            .take(n)
+            .collect::<Vec<_>>();
+        recent.reverse();
+        recent
+    }
+}
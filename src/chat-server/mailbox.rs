@@ -0,0 +1,303 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use libchat::err::MyResult;
+
+/// The maximum number of unread messages a mailbox can hold.
+pub const MAILBOX_MAX: usize = 100;
+
+/// A private message waiting to be delivered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Letter {
+    /// When the message was sent, in seconds since the Unix epoch.
+    pub time: i64,
+    pub from: String,
+    pub text: String,
+}
+
+impl Letter {
+    /// Create a letter for a message sent now.
+    pub fn now(from: &str, text: &str) -> Self {
+        Self {
+            time: Utc::now().timestamp(),
+            from: from.to_string(),
+            text: text.to_string(),
+        }
+    }
+}
+
+/// Persistent storage for private messages sent to users who are offline.
+///
+/// Every user has their own mailbox, which is a JSON Lines file named after
+/// the user in the mailbox directory, with one `Letter` per line. A letter is
+/// synced to disk before `post()` returns, and a mailbox is only removed once
+/// its letters have been delivered.
+///
+/// The names of accounts created before user names were restricted (see
+/// `libchat::check_new_username()`) may contain any character, so the file
+/// name escapes every byte other than those allowed now (see `file_name()`).
+#[derive(Debug)]
+pub struct Mailbox {
+    dir: PathBuf,
+}
+
+impl Mailbox {
+    /// Open the mailbox directory `dir`, creating it if needed.
+    pub fn open(dir: impl AsRef<Path>) -> MyResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|err| {
+            format!(
+                "failed to create mailbox directory: {}: {}",
+                dir.display(),
+                err
+            )
+        })?;
+        debug!(dir = %dir.display(), "opened mailbox directory");
+        Ok(Self { dir })
+    }
+
+    /// Add `letter` to the mailbox of `user` and return whether there was
+    /// room for it (see `MAILBOX_MAX`).
+    pub fn post(&self, user: &str, letter: &Letter) -> MyResult<bool> {
+        if self.count(user)? >= MAILBOX_MAX {
+            return Ok(false);
+        }
+
+        let mut line = serde_json::to_string(letter)
+            .map_err(|e| format!("failed to encode letter: {}", e))?;
+        line.push('\n');
+
+        let path = self.path(user);
+        let created = !path.exists();
+        let mut file =
+            OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        if created {
+            // The new file itself only survives a crash once the directory
+            // entry is on disk too
+            self.sync_dir()?;
+        }
+        Ok(true)
+    }
+
+    /// Return every letter in the mailbox of `user`, oldest first.
+    ///
+    /// The letters stay in the mailbox until `clear()` is called.
+    pub fn read(&self, user: &str) -> MyResult<Vec<Letter>> {
+        let path = self.path(user);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(Vec::new())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut letters = Vec::new();
+        for (line_no, line) in BufReader::new(file).lines().enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(letter) => letters.push(letter),
+                Err(error) => info!(
+                    path = %path.display(),
+                    line_no,
+                    %error,
+                    "skipping invalid mailbox line"
+                ),
+            }
+        }
+        Ok(letters)
+    }
+
+    /// Remove the oldest `n` letters from the mailbox of `user`, e.g. once they
+    /// have been delivered, keeping any letters posted since.
+    ///
+    /// The remaining letters are written to a temporary file which is then
+    /// renamed over the mailbox, so a crash leaves either the old or the new
+    /// mailbox.
+    pub fn remove(&self, user: &str, n: usize) -> MyResult<()> {
+        let letters = self.read(user)?;
+        if n >= letters.len() {
+            return self.clear(user);
+        }
+
+        let path = self.path(user);
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let write_tmp = || -> MyResult<()> {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for letter in &letters[n..] {
+                let line = serde_json::to_string(letter)
+                    .map_err(|e| format!("failed to encode letter: {}", e))?;
+                writeln!(writer, "{}", line)?;
+            }
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_data()?;
+            Ok(())
+        };
+        if let Err(err) = write_tmp() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+
+        fs::rename(&tmp_path, &path)?;
+        self.sync_dir()
+    }
+
+    /// Remove every letter from the mailbox of `user`.
+    pub fn clear(&self, user: &str) -> MyResult<()> {
+        match fs::remove_file(self.path(user)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the number of letters in the mailbox of `user`.
+    fn count(&self, user: &str) -> MyResult<usize> {
+        match File::open(self.path(user)) {
+            Ok(f) => Ok(BufReader::new(f).lines().count()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Flush the mailbox directory to disk, so that files created in or
+    /// renamed into it are durable.
+    fn sync_dir(&self) -> MyResult<()> {
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    #[inline]
+    fn path(&self, user: &str) -> PathBuf {
+        self.dir.join(file_name(user))
+    }
+}
+
+/// Return the name of the mailbox file of `user`.
+///
+/// ASCII letters, digits, `_` and `-` are kept, and every other byte is
+/// written as `%` followed by two hex digits, so that no name can leave the
+/// mailbox directory or clash with another.
+fn file_name(user: &str) -> String {
+    let mut name = String::with_capacity(user.len() + ".jsonl".len());
+    for b in user.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name.push_str(".jsonl");
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Return a mailbox in an empty directory for the test called `name`.
+    fn test_mailbox(name: &str) -> Mailbox {
+        let dir = env::temp_dir().join(format!(
+            "mailbox-test-{}-{}",
+            process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        Mailbox::open(&dir).unwrap()
+    }
+
+    fn texts(letters: &[Letter]) -> Vec<String> {
+        letters.iter().map(|l| l.text.clone()).collect()
+    }
+
+    #[test]
+    fn post_read_clear() {
+        let mailbox = test_mailbox("post");
+        assert!(mailbox.read("bob").unwrap().is_empty());
+        assert_eq!(mailbox.count("bob").unwrap(), 0);
+
+        assert!(mailbox.post("bob", &Letter::now("alice", "one")).unwrap());
+        assert!(mailbox.post("bob", &Letter::now("carol", "two")).unwrap());
+        assert!(mailbox.post("dave", &Letter::now("alice", "hi")).unwrap());
+        assert_eq!(mailbox.count("bob").unwrap(), 2);
+
+        let letters = mailbox.read("bob").unwrap();
+        assert_eq!(texts(&letters), ["one", "two"]);
+        assert_eq!(letters[1].from, "carol");
+        // Reading doesn't remove anything
+        assert_eq!(mailbox.count("bob").unwrap(), 2);
+
+        mailbox.clear("bob").unwrap();
+        assert!(mailbox.read("bob").unwrap().is_empty());
+        assert_eq!(mailbox.count("dave").unwrap(), 1);
+        // Clearing an empty mailbox is fine
+        mailbox.clear("bob").unwrap();
+
+        fs::remove_dir_all(&mailbox.dir).unwrap();
+    }
+
+    #[test]
+    fn remove_keeps_newer_letters() {
+        let mailbox = test_mailbox("remove");
+        for text in ["one", "two", "three"] {
+            mailbox.post("bob", &Letter::now("alice", text)).unwrap();
+        }
+
+        mailbox.remove("bob", 2).unwrap();
+        assert_eq!(texts(&mailbox.read("bob").unwrap()), ["three"]);
+        mailbox.remove("bob", 5).unwrap();
+        assert!(!mailbox.path("bob").exists());
+
+        fs::remove_dir_all(&mailbox.dir).unwrap();
+    }
+
+    #[test]
+    fn full_mailbox_rejects_letters() {
+        let mailbox = test_mailbox("full");
+        for n in 0..MAILBOX_MAX {
+            let letter = Letter::now("alice", &n.to_string());
+            assert!(mailbox.post("bob", &letter).unwrap());
+        }
+        assert!(!mailbox.post("bob", &Letter::now("alice", "late")).unwrap());
+        assert_eq!(mailbox.count("bob").unwrap(), MAILBOX_MAX);
+        assert_eq!(
+            mailbox.read("bob").unwrap().last().unwrap().text,
+            (MAILBOX_MAX - 1).to_string()
+        );
+
+        // Room is made by removing letters
+        mailbox.remove("bob", 1).unwrap();
+        assert!(mailbox.post("bob", &Letter::now("alice", "late")).unwrap());
+
+        fs::remove_dir_all(&mailbox.dir).unwrap();
+    }
+
+    #[test]
+    fn file_names_are_escaped() {
+        assert_eq!(file_name("bob_1-x"), "bob_1-x.jsonl");
+        assert_eq!(file_name("bob.s"), "bob%2Es.jsonl");
+        assert_eq!(file_name("../x"), "%2E%2E%2Fx.jsonl");
+        assert_eq!(file_name("é"), "%C3%A9.jsonl");
+
+        let mailbox = test_mailbox("escaped");
+        mailbox.post("../x", &Letter::now("alice", "hi")).unwrap();
+        assert_eq!(texts(&mailbox.read("../x").unwrap()), ["hi"]);
+        assert!(mailbox.dir.join("%2E%2E%2Fx.jsonl").exists());
+
+        fs::remove_dir_all(&mailbox.dir).unwrap();
+    }
+}
@@ -1,13 +1,32 @@
-use std::{path::PathBuf, process::exit};
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+    process::exit,
+};
 
-use dotenv;
-use tracing::level_filters::STATIC_MAX_LEVEL;
-use tracing_subscriber;
+use clap::Parser;
+use tracing::{debug, info};
 
-use libchat::{err::MyResult, print_server_banner, UsersDao, CHAT_PORT};
+use libchat::{
+    err::MyResult,
+    logging::init_logging,
+    open_user_store, print_server_banner,
+    sys::{ServerSocket, SockAddr, SocketCommon, UnixServerSocket},
+};
+
+mod chat_log;
+
+mod config;
+use config::{Args, Config};
+
+mod history;
+
+mod mailbox;
 
 mod server;
-use server::TcpServer;
+use server::ChatServer;
 
 fn main() {
     if let Err(err) = run() {
@@ -18,15 +37,73 @@ fn main() {
 }
 
 fn run() -> MyResult<()> {
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .with_max_level(STATIC_MAX_LEVEL)
-        .init();
+    let args = Args::parse();
+    let print_config = args.print_config;
+    let config = Config::load(args)?;
+    if print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
 
+    init_logging(
+        &config.log_level,
+        config.log_format,
+        config.log_file.as_deref(),
+        true,
+    )?;
     print_server_banner();
 
-    let users_db = UsersDao::from(PathBuf::from(dotenv::var("USERS_DB")?))?;
-    TcpServer::new(CHAT_PORT, users_db)?.main_loop()?;
+    let users_db = open_user_store(config.users_backend, &config.users_db)?;
+
+    match config.socket.clone() {
+        Some(path) => ChatServer::new(listen_unix(&path)?, config, users_db)?
+            .main_loop()?,
+        None => {
+            let addr = SockAddr::new(config.host, config.port);
+            ChatServer::new(listen_tcp(&addr)?, config, users_db)?
+                .main_loop()?
+        }
+    }
 
     Ok(())
 }
+
+/// Create a TCP socket listening on `addr`.
+fn listen_tcp(addr: &SockAddr) -> MyResult<ServerSocket> {
+    let sock = ServerSocket::new(addr.family())?;
+    sock.bind(addr)?;
+    sock.listen()?;
+    debug!(sock=%sock.fd(), %addr, "created server socket");
+    Ok(sock)
+}
+
+/// Create a Unix domain socket listening at `path`.
+///
+/// A socket file left behind by a server that didn't exit cleanly is removed
+/// first, but not one that a running server is still listening on.
+fn listen_unix(path: &Path) -> MyResult<UnixServerSocket> {
+    let is_socket = fs::symlink_metadata(path)
+        .is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket {
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(format!(
+                    "another server is already listening on {}",
+                    path.display()
+                )
+                .into())
+            }
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                info!(path = %path.display(), "removing stale socket file");
+                fs::remove_file(path)?;
+            }
+            Err(_) => {}
+        }
+    }
+
+    let mut sock = UnixServerSocket::new()?;
+    sock.bind(path)?;
+    sock.listen()?;
+    debug!(sock=%sock.fd(), path = %path.display(), "created server socket");
+    Ok(sock)
+}
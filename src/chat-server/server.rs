@@ -1,39 +1,89 @@
 use std::{
-    collections::hash_map::Entry,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    iter,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use libc::POLLIN;
+use libc::c_int;
 use libchat::{
-    err::MyResult,
+    check_message, check_new_username, check_password, check_room,
+    check_username,
+    err::{MyError, MyResult},
+    protocol::{
+        Command, Feature, HandshakeReply, Hello, Push, RejectReason, Reply,
+        ServerMsg, PROTOCOL_VERSION, PROTOCOL_VERSION_MIN,
+    },
     setup_int_handler,
-    sys::{errno_was_intr, ServerSocket, SockAddr, SocketCommon},
-    UsersDao, COMMAND_MAX, COMMAND_SEP, HANDSHAKE_ACK, REPLY_FLAG_ERR,
+    sys::{wait_ready, Listener, SockAddr, SocketCommon},
+    UserStore, DEFAULT_ROOM, E_NOT_LOGGED_IN, E_NOT_LOGGED_OUT,
+    HANDSHAKE_TIMEOUT, SEND_QUEUE_MAX,
+};
+use tracing::{debug, field, info, info_span, Span};
+
+use super::{
+    chat_log::{ChatLog, Event, Record},
+    config::Config,
+    history::{Entry, History},
+    mailbox::{Letter, Mailbox},
 };
-use tracing::{debug, info};
 
-pub struct TcpServer {
-    sock: ServerSocket,
-    users: UsersDao,
+pub struct ChatServer<L: Listener> {
+    sock: L,
+    config: Config,
+    users: Box<dyn UserStore>,
+    clients: HashMap<c_int, Client<L>>,
+    history: History,
+    chat_log: Option<ChatLog>,
+    mailbox: Mailbox,
+    /// When the remaining clients are disconnected, once the server is
+    /// shutting down (see `begin_shutdown()`).
+    shutdown_at: Option<Instant>,
 }
 
 /// Wrapper type that manages server-side networking.
 ///
+/// The server works the same over any kind of listening socket, e.g. a TCP
+/// `ServerSocket` or a `UnixServerSocket`, which must already be listening.
+///
 /// The only provided method is `main_loop()` which runs the server, accepting
-/// connections and processing commands from the client.
-impl TcpServer {
-    pub fn new(port: u16, users: UsersDao) -> MyResult<Self> {
-        let sock = ServerSocket::new()?;
-        let mut addr = SockAddr::new(port);
-        sock.bind(&mut addr)?;
-        sock.listen()?;
-        debug!(sock=%sock.fd(), "created server socket");
-        Ok(Self { sock, users })
+/// connections and processing commands from all connected clients until it is
+/// asked to stop by a signal.
+impl<L: Listener> ChatServer<L> {
+    pub fn new(
+        sock: L,
+        config: Config,
+        users: Box<dyn UserStore>,
+    ) -> MyResult<Self> {
+        // Rebuild the history from the chat log, if there is one
+        let mut history = History::new(config.history_size);
+        let chat_log = match &config.chat_log {
+            Some(path) => {
+                let log = ChatLog::open(
+                    path,
+                    config.chat_log_max_size,
+                    config.chat_log_keep,
+                )?;
+                history.extend(log.recent_messages(config.history_size)?);
+                Some(log)
+            }
+            None => None,
+        };
+        let mailbox = Mailbox::open(&config.mailbox_dir)?;
+
+        Ok(Self {
+            sock,
+            config,
+            users,
+            clients: HashMap::new(),
+            history,
+            chat_log,
+            mailbox,
+            shutdown_at: None,
+        })
     }
 
     //==================================================
@@ -41,138 +91,421 @@ impl TcpServer {
     //==================================================
 
     /// Run the server.
+    ///
+    /// The first interrupt or termination signal starts a graceful shutdown
+    /// (see `begin_shutdown()`): the server keeps serving the connected
+    /// clients until the grace period is over or every one of them has left.
+    /// A second signal stops the server right away.
     pub fn main_loop(&mut self) -> MyResult<()> {
         let should_stop = Arc::new(AtomicBool::new(false));
-        setup_int_handler(&should_stop)?;
-
-        // Sleep after each loop iter to prevent CPU overload
-        let delay = Duration::from_millis(25);
-
-        let mut maybe_client = None;
+        let sig_pipe = setup_int_handler(&should_stop)?;
+        // Stops being waited on if it can't be drained, see below
+        let mut sig_fd = Some(sig_pipe.fd());
 
         loop {
-            thread::sleep(delay);
-
-            if should_stop.load(Ordering::Relaxed) {
-                break;
-            }
-
-            // Poll for incoming connection
-            match self.sock.poll(POLLIN) {
-                Ok(has_incoming) if has_incoming => {
-                    // Accept all incoming connections, but drop them if there
-                    // is already a client
-                    match self.sock.accept() {
-                        Err(error) => {
-                            info!(%error, "failed to accept potential new client")
-                        }
-                        Ok(s) if maybe_client.is_none() => {
-                            // Send handshake ack
-                            if let Err(error) = s.send(HANDSHAKE_ACK) {
-                                info!(
-                                    sock = s.fd(),
-                                    %error,
-                                    "failed to send connection accepted message to client"
-                                );
-                            } else {
-                                // No error, store client
-                                maybe_client = Some(Client::new(s));
-                            }
-                        }
-                        // New connection is dropped if !maybe_client.is_none()
-                        _ => (),
-                    }
+            if should_stop.swap(false, Ordering::Relaxed) {
+                if self.shutdown_at.is_some() {
+                    info!("stopping without waiting for clients");
+                    break;
                 }
-                Err(error) => {
-                    if errno_was_intr() {
-                        // Stop server for interrupt signals
-                        break;
-                    } else {
-                        info!(
-                            %error,
-                            "failed to poll for potential new client"
-                        );
-                    }
-                }
-                _ => (),
+                self.begin_shutdown();
             }
 
-            if let Some(client) = &mut maybe_client {
-                if !self.handle_connection(client) {
-                    // Drop and close client socket.
-                    maybe_client.take();
+            let timeout = match self.shutdown_at {
+                Some(_) if self.clients.is_empty() => break,
+                Some(at) => match at.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => break,
+                },
+                None => None,
+            };
+            // Wake up in time to drop connections stuck in the handshake
+            let timeout = match (timeout, self.next_handshake_deadline()) {
+                (Some(left), Some(at)) => {
+                    Some(left.min(at.saturating_duration_since(Instant::now())))
                 }
+                (None, Some(at)) => {
+                    Some(at.saturating_duration_since(Instant::now()))
+                }
+                (timeout, None) => timeout,
+            };
+
+            // Wait on the signal pipe, the server socket and every client
+            // socket at once, until one of them is ready. Clients with queued
+            // output are also waited on until they can take more of it.
+            let fds = sig_fd
+                .into_iter()
+                .chain(iter::once(self.sock.fd()))
+                .chain(self.clients.keys().copied())
+                .collect::<Vec<_>>();
+            let write_fds = self
+                .clients
+                .iter()
+                .filter(|(_, client)| client.sock.queued_len() > 0)
+                .map(|(&fd, _)| fd)
+                .collect::<Vec<_>>();
+
+            let (readable, writable) =
+                match wait_ready(&fds, &write_fds, timeout) {
+                    Ok(ready) => ready,
+                    Err(error) => {
+                        // Errors other than an interrupt (e.g. `EBADF` or
+                        // `ENOMEM`) would only happen again right away
+                        self.finish_shutdown();
+                        return Err(error);
+                    }
+                };
+
+            for fd in writable {
+                self.flush_client(fd);
             }
+            for fd in readable {
+                if Some(fd) == sig_fd {
+                    // The stop flag is checked at the top of the loop. A pipe
+                    // that can't be drained would stay ready, so it is no
+                    // longer waited on. Signals still interrupt the wait.
+                    if let Err(error) = sig_pipe.drain() {
+                        info!(%error, "failed to drain signal pipe");
+                        sig_fd = None;
+                    }
+                } else if fd == self.sock.fd() {
+                    self.accept_client();
+                } else {
+                    self.service_client(fd);
+                }
+            }
+            self.drop_backlogged_clients();
+            self.drop_stalled_handshakes();
         }
 
+        self.finish_shutdown();
         Ok(())
     }
 
-    /// Parse and process a command from the client and return whether the
-    /// client should be kept (i.e. false means drop the client).
-    fn handle_connection(&mut self, client: &mut Client) -> bool {
-        match client.sock.poll(POLLIN) {
-            Ok(has_data) if !has_data => return true,
-            Err(error) => {
+    /// Start shutting down: tell every client that the server is shutting
+    /// down and when it will disconnect them, and reject new connections
+    /// from now on.
+    ///
+    /// Clients that did not negotiate shutdown notices are not told, they only
+    /// see the connection being closed.
+    fn begin_shutdown(&mut self) {
+        let grace = self.config.shutdown_grace;
+        let reason = self.config.shutdown_reason.clone();
+        info!(
+            grace,
+            ?reason,
+            n_clients = self.clients.len(),
+            "shutting down"
+        );
+
+        let msg = ServerMsg::Push(Push::Shutdown { grace, reason });
+        for client in self.clients.values() {
+            if !client.accepts_pushes(Feature::Shutdown) {
+                continue;
+            }
+            if let Err(error) = client.send_msg(&msg) {
                 info!(
-                    sock = %client.sock.fd(),
+                    parent: &client.span,
                     %error,
-                    "failed to poll for client message"
+                    "failed to push shutdown notice"
                 );
-                return false;
             }
-            _ => (),
         }
 
-        let cmd = match client.sock.recv(COMMAND_MAX) {
-            Ok(c) => c,
+        self.shutdown_at =
+            Some(Instant::now() + Duration::from_secs(grace.into()));
+    }
+
+    /// End the session of every remaining client and flush the user store and
+    /// the chat log to disk.
+    ///
+    /// The clients are disconnected when they are dropped at the end. Whatever
+    /// is still queued for them is written if their sockets take it right
+    /// away, and lost otherwise.
+    fn finish_shutdown(&mut self) {
+        let mut clients = std::mem::take(&mut self.clients);
+        for client in clients.values_mut() {
+            let _span = client.span.clone().entered();
+            if let Some(user) = self.end_session(client) {
+                println!("{} disconnected.", user);
+            }
+        }
+        for client in clients.values_mut() {
+            let _ = client.sock.flush_send_buf();
+            self.remove_delivered_letters(client);
+        }
+
+        if let Err(error) = self.users.flush() {
+            info!(%error, "failed to flush user store");
+        }
+        if let Some(log) = &mut self.chat_log {
+            if let Err(error) = log.flush() {
+                info!(%error, "failed to flush chat log");
+            }
+        }
+        debug!(n_clients = clients.len(), "drop remaining clients");
+    }
+
+    /// Accept an incoming connection and store it as a new client.
+    ///
+    /// The client must complete the handshake before it can send commands,
+    /// within `HANDSHAKE_TIMEOUT` (see `drop_stalled_handshakes()`). While
+    /// `max_clients` connections are waiting to complete it, new connections
+    /// are closed right away.
+    /// Its socket is made non-blocking, so that a client that stops reading
+    /// can't stall the server (see `Client::send_msg()`).
+    fn accept_client(&mut self) {
+        match self.sock.accept() {
+            Ok((s, addr)) => {
+                let client = Client::new(s, addr);
+                let n_pending = self
+                    .clients
+                    .values()
+                    .filter(|c| c.state == Session::Handshake)
+                    .count();
+                if n_pending >= self.config.max_clients {
+                    client.span.in_scope(|| {
+                        info!(n_pending, "too many pending handshakes, closing")
+                    });
+                    return;
+                }
+                if let Err(error) = client.sock.set_nonblocking() {
+                    client.span.in_scope(
+                        || info!(%error, "failed to set up new client"),
+                    );
+                    return;
+                }
+                client.span.in_scope(|| {
+                    debug!(n_clients = self.clients.len() + 1, "new client")
+                });
+                self.clients.insert(client.sock.fd(), client);
+            }
             Err(error) => {
-                info!(sock = %client.sock.fd(),
-                    %error,
-                    "failed to recv from client"
-                );
-                return false;
+                info!(%error, "failed to accept potential new client");
             }
+        }
+    }
+
+    /// Process commands from the client with socket `fd`, dropping the client
+    /// if the connection should not be kept.
+    ///
+    /// This should only be called once the client socket is ready for reading.
+    /// The client is taken out of `self.clients` while its commands are being
+    /// handled so that commands have mutable access to the rest of the server.
+    fn service_client(&mut self, fd: c_int) {
+        let mut client = match self.clients.remove(&fd) {
+            Some(c) => c,
+            None => return,
         };
-        debug!(sock = %client.sock.fd(), ?cmd, "received command");
 
-        let cmd: Vec<_> = cmd.split(COMMAND_SEP).collect();
-        if cmd.is_empty() {
-            info!(sock = %client.sock.fd(), "received empty command");
-            return false;
+        if self.recv_commands(&mut client) {
+            self.clients.insert(fd, client);
+        } else {
+            self.drop_client(client);
         }
+    }
 
-        macro_rules! reply_invalid_num_args {
-            ($expected:expr, $actual:expr) => {
-                client.reply_err(format!(
-                    "expected {} arguments but got {}",
-                    $expected, $actual
-                ))
-            };
+    /// Write as much of the queued output of the client with socket `fd` as
+    /// its socket takes, dropping the client if writing fails.
+    ///
+    /// This should only be called once the client socket is ready for writing.
+    fn flush_client(&mut self, fd: c_int) {
+        let mut client = match self.clients.remove(&fd) {
+            Some(c) => c,
+            None => return,
+        };
+        match client.sock.flush_send_buf() {
+            Ok(_) => {
+                self.remove_delivered_letters(&mut client);
+                self.clients.insert(fd, client);
+            }
+            Err(error) => {
+                client
+                    .span
+                    .in_scope(|| info!(%error, "failed to send to client"));
+                self.drop_client(client);
+            }
         }
+    }
 
-        let mut keep_connection = true;
+    /// Drop every client that has more than `SEND_QUEUE_MAX` bytes of output
+    /// queued, i.e. that stopped reading what the server sends it.
+    ///
+    /// Dropping a client may push logout notices to the others, so this goes
+    /// on until no client is over the limit.
+    fn drop_backlogged_clients(&mut self) {
+        while let Some(fd) = self
+            .clients
+            .iter()
+            .find(|(_, client)| client.sock.queued_len() > SEND_QUEUE_MAX)
+            .map(|(&fd, _)| fd)
+        {
+            if let Some(client) = self.clients.remove(&fd) {
+                client.span.in_scope(|| {
+                    info!(
+                        queued = client.sock.queued_len(),
+                        "client is not reading, dropping it"
+                    )
+                });
+                self.drop_client(client);
+            }
+        }
+    }
 
-        let cmd_ret = match cmd.as_slice() {
-            ["newuser", user, pass] => self.cmd_newuser(client, user, pass),
-            ["newuser", rest @ ..] => reply_invalid_num_args!(2, rest.len()),
+    /// Return when the first client that is still in the handshake runs out of
+    /// time to complete it, if there is any such client.
+    fn next_handshake_deadline(&self) -> Option<Instant> {
+        self.clients
+            .values()
+            .filter(|c| c.state == Session::Handshake)
+            .map(|c| c.connected_at + HANDSHAKE_TIMEOUT)
+            .min()
+    }
 
-            ["login", user, pass] => self.cmd_login(client, user, pass),
-            ["login", rest @ ..] => reply_invalid_num_args!(2, rest.len()),
+    /// Reject and drop every client that has not completed the handshake
+    /// within `HANDSHAKE_TIMEOUT` of connecting.
+    fn drop_stalled_handshakes(&mut self) {
+        let now = Instant::now();
+        let stalled = self
+            .clients
+            .iter()
+            .filter(|(_, c)| {
+                c.state == Session::Handshake
+                    && now >= c.connected_at + HANDSHAKE_TIMEOUT
+            })
+            .map(|(&fd, _)| fd)
+            .collect::<Vec<_>>();
 
-            ["logout"] => {
-                keep_connection = false;
-                self.cmd_logout(client)
+        for fd in stalled {
+            if let Some(client) = self.clients.remove(&fd) {
+                client.span.in_scope(|| info!("handshake timed out"));
+                let reply = HandshakeReply::Reject {
+                    reason: RejectReason::BadHandshake,
+                    message: "No hello received in time.".to_string(),
+                };
+                let _ = client.sock.queue(reply.encode());
+                self.drop_client(client);
             }
-            ["logout", rest @ ..] => reply_invalid_num_args!(0, rest.len()),
+        }
+    }
 
-            ["send", msg] => self.cmd_send(client, msg),
-            ["send", rest @ ..] => reply_invalid_num_args!(2, rest.len()),
+    /// End the session of `client`, which is no longer in `self.clients`, and
+    /// drop it.
+    fn drop_client(&mut self, mut client: Client<L>) {
+        let _span = client.span.clone().entered();
+        // Letters that can't be written now are delivered on the next login
+        let _ = client.sock.flush_send_buf();
+        self.remove_delivered_letters(&mut client);
+        // A client that disconnects without logging out still leaves
+        if let Some(user) = self.end_session(&mut client) {
+            println!("{} disconnected.", user);
+        }
+        // Client is dropped and its socket closed
+        debug!(n_clients = self.clients.len(), "drop client");
+    }
 
-            _ => client.reply_err(format!(
-                "Error. Command not recognized: {}",
-                cmd[0]
-            )),
+    /// Receive and handle every complete command from the client and return
+    /// whether the client should be kept (i.e. false means drop the client).
+    ///
+    /// Everything logged while receiving or handling a command is in the
+    /// client's span. The span is entered anew for every command, since
+    /// logging in or out replaces it.
+    fn recv_commands(&mut self, client: &mut Client<L>) -> bool {
+        let span = client.span.clone();
+        match span.in_scope(|| client.sock.fill_recv_buf()) {
+            Ok(()) => (),
+            Err(MyError::ConnectionClosed) => {
+                span.in_scope(|| info!("client closed the connection"));
+                return false;
+            }
+            Err(error) => {
+                span.in_scope(|| info!(%error, "failed to recv from client"));
+                return false;
+            }
+        }
+
+        loop {
+            let _span = client.span.clone().entered();
+            match client.sock.recv_buffered() {
+                Ok(Some(cmd)) => {
+                    if !self.handle_connection(client, &cmd) {
+                        return false;
+                    }
+                }
+                Ok(None) => return true,
+                Err(error) => {
+                    info!(%error, "failed to recv from client");
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Parse and process a command from the client and return whether the
+    /// client should be kept (i.e. false means drop the client).
+    fn handle_connection(&mut self, client: &mut Client<L>, cmd: &str) -> bool {
+        debug!(?cmd, "received command");
+
+        if client.state == Session::Handshake {
+            return self.handshake(client, cmd);
+        }
+
+        let cmd = match Command::decode(cmd) {
+            Ok(c) => c,
+            Err(error) => {
+                if let Err(error) = client.reply_err(error.to_string()) {
+                    info!(%error, "error while replying to invalid command");
+                }
+                return true;
+            }
+        };
+
+        let mut keep_connection = true;
+
+        // Every command is checked against the session state here, so the
+        // commands themselves may assume they are allowed to run.
+        let cmd_ret = match (&cmd, &client.state) {
+            (Command::NewUser { user, pass }, Session::LoggedOut) => {
+                self.cmd_newuser(client, user, pass)
+            }
+            (Command::Login { user, pass }, Session::LoggedOut) => {
+                self.cmd_login(client, user, pass)
+            }
+            (Command::Logout, Session::LoggedIn(_)) => {
+                keep_connection = false;
+                self.cmd_logout(client)
+            }
+            (Command::Send { msg }, Session::LoggedIn(user)) => {
+                self.cmd_send(client, user, msg)
+            }
+            (Command::Join { room }, Session::LoggedIn(_)) => {
+                self.cmd_join(client, room)
+            }
+            (Command::Leave { room }, Session::LoggedIn(_)) => {
+                self.cmd_leave(client, room)
+            }
+            (Command::Rooms, Session::LoggedIn(_)) => self.cmd_rooms(client),
+            (Command::Msg { to, text }, Session::LoggedIn(user)) => {
+                self.cmd_msg(client, user, to, text)
+            }
+            (Command::Who, Session::LoggedIn(_)) => self.cmd_who(client),
+            (Command::History { count }, Session::LoggedIn(_)) => {
+                self.cmd_history(client, *count)
+            }
+            (Command::NewUser { .. } | Command::Login { .. }, _) => {
+                client.reply_err(E_NOT_LOGGED_OUT)
+            }
+            (
+                Command::Logout
+                | Command::Send { .. }
+                | Command::Join { .. }
+                | Command::Leave { .. }
+                | Command::Rooms
+                | Command::Msg { .. }
+                | Command::Who
+                | Command::History { .. },
+                _,
+            ) => client.reply_err(E_NOT_LOGGED_IN),
         };
 
         if let Err(error) = cmd_ret {
@@ -182,6 +515,87 @@ impl TcpServer {
         keep_connection
     }
 
+    /// Process the hello message from a new client and reply with either the
+    /// negotiated protocol parameters or the reason for rejecting the client.
+    /// Return whether the client should be kept.
+    ///
+    /// Rejections are only sent in reply to the hello, even if the reason was
+    /// already known when the connection was accepted. Closing a socket with
+    /// unread data in it resets the connection, which could discard the
+    /// rejection before the client reads it.
+    fn handshake(&self, client: &mut Client<L>, msg: &str) -> bool {
+        let n_clients = self
+            .clients
+            .values()
+            .filter(|c| c.negotiated.is_some())
+            .count();
+
+        let reject = |reason, message: String| HandshakeReply::Reject {
+            reason,
+            message,
+        };
+
+        let reply = match Hello::decode(msg) {
+            _ if client
+                .addr
+                .ip()
+                .is_some_and(|ip| self.config.banned.contains(&ip)) =>
+            {
+                reject(
+                    RejectReason::Banned,
+                    "Your address is not allowed to connect.".to_string(),
+                )
+            }
+            _ if self.shutdown_at.is_some() => reject(
+                RejectReason::ShuttingDown,
+                "The server is shutting down.".to_string(),
+            ),
+            Err(error) => reject(RejectReason::BadHandshake, error.to_string()),
+            Ok(hello) if hello.version < PROTOCOL_VERSION_MIN => reject(
+                RejectReason::VersionMismatch,
+                format!(
+                    "protocol version {} is not supported (minimum is {})",
+                    hello.version, PROTOCOL_VERSION_MIN
+                ),
+            ),
+            Ok(_) if n_clients >= self.config.max_clients => reject(
+                RejectReason::ServerFull,
+                format!(
+                    "The server is full ({} clients), try again later.",
+                    self.config.max_clients
+                ),
+            ),
+            Ok(hello) => {
+                // Speak the newest version both sides know, and enable every
+                // feature both sides support.
+                let negotiated = Negotiated {
+                    version: hello.version.min(PROTOCOL_VERSION),
+                    features: hello
+                        .features
+                        .into_iter()
+                        .filter(|f| Feature::ALL.contains(f))
+                        .collect(),
+                };
+                let reply = HandshakeReply::Welcome {
+                    version: negotiated.version,
+                    server_name: self.config.name.clone(),
+                    features: negotiated.features.clone(),
+                };
+                client.negotiated = Some(negotiated);
+                client.state = Session::LoggedOut;
+                reply
+            }
+        };
+        debug!(?reply, "handshake");
+
+        if let Err(error) = client.sock.queue(reply.encode()) {
+            info!(%error, "failed to send handshake reply to client");
+            return false;
+        }
+
+        client.negotiated.is_some()
+    }
+
     //==================================================
     // Commands
     //==================================================
@@ -191,15 +605,33 @@ impl TcpServer {
     /// This command can only be called when **not** logged in.
     fn cmd_newuser(
         &mut self,
-        client: &Client,
+        client: &Client<L>,
         user: &str,
         pass: &str,
     ) -> MyResult<()> {
-        if self.users.insert(user.to_string(), pass.to_string()) {
-            println!("New user account created.");
-            client.reply_ok("New user account created. Please login.")
-        } else {
-            client.reply_err("Denied. User account already exists.")
+        // Names of new accounts are restricted to characters that need no
+        // quoting anywhere a name ends up, e.g. the text users database,
+        // where `,` and `)` end a field. Existing accounts are not checked,
+        // so that older ones with other names can still log in.
+        if let Err(msg) = check_username(user)
+            .and(check_new_username(user))
+            .and(check_password(pass))
+        {
+            return client.reply_err(msg);
+        }
+
+        match self.users.create_user(user, pass) {
+            Ok(true) => {
+                println!("New user account created.");
+                client.reply_ok("New user account created. Please login.")
+            }
+            Ok(false) => {
+                client.reply_err("Denied. User account already exists.")
+            }
+            Err(error) => {
+                client.reply_err("Error. Failed to create user account.")?;
+                Err(error)
+            }
         }
     }
 
@@ -208,88 +640,694 @@ impl TcpServer {
     /// This command can only be called when **not** logged in.
     fn cmd_login(
         &mut self,
-        client: &mut Client,
+        client: &mut Client<L>,
         user: &str,
         pass: &str,
     ) -> MyResult<()> {
-        match &self.users.entry(user) {
-            Entry::Occupied(oe) if oe.get() == pass => {
+        match self.users.verify(user, pass) {
+            Ok(true) => {
                 client.login(user);
                 println!("{} login.", user);
-                client.reply_ok("Login confirmed.")
+                self.log_event(Event::Join {
+                    room: DEFAULT_ROOM.to_string(),
+                    user: user.to_string(),
+                });
+                self.broadcast(Push::Login {
+                    user: user.to_string(),
+                });
+
+                // Unread messages can only be delivered as pushes
+                let letters = if client.accepts_pushes(Feature::Unread) {
+                    self.mailbox.read(user).unwrap_or_else(|error| {
+                        info!(%error, user, "failed to read mailbox");
+                        Vec::new()
+                    })
+                } else {
+                    Vec::new()
+                };
+                let mut reply = match letters.len() {
+                    0 => "Login confirmed.".to_string(),
+                    1 => "Login confirmed. You have 1 unread message."
+                        .to_string(),
+                    n => format!(
+                        "Login confirmed. You have {} unread messages.",
+                        n
+                    ),
+                };
+                // The message of the day goes on the following lines
+                if let Some(motd) = &self.config.motd {
+                    reply.push('\n');
+                    reply.push_str(motd);
+                }
+                client.reply_ok(reply)?;
+
+                self.replay_history(client, self.config.history_replay);
+                self.deliver_letters(client, user, letters)
+            }
+            Ok(false) => {
+                client.reply_err("Denied. User name or password incorrect.")
+            }
+            Err(error) => {
+                client.reply_err("Error. Failed to look up user account.")?;
+                Err(error)
             }
-            _ => client.reply_err("Denied. User name or password incorrect."),
         }
     }
 
     /// Invoke the logout command.
     ///
     /// This command can only be called when logged in.
-    fn cmd_logout(&self, client: &mut Client) -> MyResult<()> {
-        if let Some(user) = client.logout() {
-            println!("{} logout.", user);
-            client.reply_ok(format!("{} left.", user))
-        } else {
-            Ok(())
-        }
+    fn cmd_logout(&mut self, client: &mut Client<L>) -> MyResult<()> {
+        let user = self.end_session(client).unwrap_or_default();
+        println!("{} logout.", user);
+        client.reply_ok(format!("{} left.", user))
     }
 
     /// Invoke the send command.
     ///
+    /// The message is pushed to every other member of the client's current
+    /// room and the sender gets it back as the command reply.
+    ///
+    /// This command can only be called when logged in.
+    fn cmd_send(
+        &mut self,
+        client: &Client<L>,
+        user: &str,
+        msg: &str,
+    ) -> MyResult<()> {
+        if let Err(msg) = check_message(msg) {
+            return client.reply_err(msg);
+        }
+        let room = match client.current_room() {
+            Some(room) => room,
+            None => return client.reply_err("Denied. Join a room first."),
+        };
+
+        println!("[{}] {}: {}", room, user, msg);
+        let entry = Entry::now(room, user, msg);
+        self.log_record(&Record::from(&entry));
+        self.history.push(entry);
+        self.broadcast_room(
+            room,
+            Push::Message {
+                room: room.to_string(),
+                from: user.to_string(),
+                text: msg.to_string(),
+            },
+        );
+        client.reply_ok(format!("[{}] {}: {}", room, user, msg))
+    }
+
+    /// Invoke the join command.
+    ///
+    /// Joining a room the client is already a member of just makes it the
+    /// current room again.
+    ///
     /// This command can only be called when logged in.
-    fn cmd_send(&self, client: &Client, msg: &str) -> MyResult<()> {
-        if let Some(user) = &client.username {
-            println!("{}: {}", user, msg);
-            client.reply_ok(format!("{}: {}", user, msg))
+    fn cmd_join(&mut self, client: &mut Client<L>, room: &str) -> MyResult<()> {
+        if let Err(msg) = check_room(room) {
+            return client.reply_err(msg);
+        }
+
+        if client.join_room(room) {
+            debug!(room, "joined room");
+            self.log_event(Event::Join {
+                room: room.to_string(),
+                user: client.username().unwrap_or_default().to_string(),
+            });
+            client.reply_ok(format!("Joined {}.", room))
         } else {
-            Ok(())
+            client.reply_ok(format!("Switched to {}.", room))
+        }
+    }
+
+    /// Invoke the leave command.
+    ///
+    /// If the current room is left, the most recently joined of the remaining
+    /// rooms becomes the current room.
+    ///
+    /// This command can only be called when logged in.
+    fn cmd_leave(
+        &mut self,
+        client: &mut Client<L>,
+        room: &str,
+    ) -> MyResult<()> {
+        if !client.leave_room(room) {
+            return client
+                .reply_err(format!("Denied. Not a member of {}.", room));
+        }
+        debug!(room, "left room");
+        self.log_event(Event::Leave {
+            room: room.to_string(),
+            user: client.username().unwrap_or_default().to_string(),
+        });
+
+        match client.current_room() {
+            Some(current) => client.reply_ok(format!(
+                "Left {}. Current room is {}.",
+                room, current
+            )),
+            None => client.reply_ok(format!("Left {}. Not in any room.", room)),
+        }
+    }
+
+    /// Invoke the rooms command.
+    ///
+    /// Every room that has at least one member is listed with its number of
+    /// members, and the client's current room is marked.
+    ///
+    /// This command can only be called when logged in.
+    fn cmd_rooms(&self, client: &Client<L>) -> MyResult<()> {
+        // The client being serviced is not in `self.clients`
+        let mut counts = BTreeMap::<&str, usize>::new();
+        for c in self.clients.values().chain(iter::once(client)) {
+            for room in &c.rooms {
+                *counts.entry(room).or_default() += 1;
+            }
+        }
+
+        if counts.is_empty() {
+            return client.reply_ok("No rooms.");
+        }
+
+        let current = client.current_room();
+        let list = counts
+            .iter()
+            .map(|(&room, n)| {
+                format!(
+                    "{} {} ({} member{})",
+                    if Some(room) == current { '*' } else { ' ' },
+                    room,
+                    n,
+                    if *n == 1 { "" } else { "s" }
+                )
+            })
+            .collect::<Vec<_>>();
+        client.reply_ok(format!("Rooms:\n{}", list.join("\n")))
+    }
+
+    /// Invoke the msg command.
+    ///
+    /// The message is pushed only to the sessions of user `to`, other than the
+    /// sending session. If none of them can receive it, e.g. because `to` is
+    /// offline or only logged in with clients that did not negotiate pushes,
+    /// it is queued in their mailbox instead.
+    ///
+    /// This command can only be called when logged in.
+    fn cmd_msg(
+        &mut self,
+        client: &Client<L>,
+        user: &str,
+        to: &str,
+        text: &str,
+    ) -> MyResult<()> {
+        if let Err(msg) = check_username(to).and(check_message(text)) {
+            return client.reply_err(msg);
+        }
+
+        // The sending session counts as a recipient of messages to oneself
+        let to_self = usize::from(to == user);
+        let n_online = self
+            .clients
+            .values()
+            .filter(|c| c.username() == Some(to))
+            .count()
+            + to_self;
+        let n_sessions = self.broadcast_where(
+            Push::Direct {
+                from: user.to_string(),
+                text: text.to_string(),
+            },
+            |c| c.username() == Some(to),
+        ) + to_self;
+
+        if n_sessions > 0 {
+            debug!(from = user, to, n_sessions, "direct message");
+            if self.config.chat_log_dms {
+                self.log_event(Event::Direct {
+                    from: user.to_string(),
+                    to: to.to_string(),
+                    text: text.to_string(),
+                });
+            }
+            return client.reply_ok(format!("-> {}: {}", to, text));
+        }
+
+        if n_online > 0 {
+            return self.post_letter(client, user, to, text, true);
+        }
+        match self.users.lookup(to) {
+            Ok(Some(_)) => self.post_letter(client, user, to, text, false),
+            Ok(None) => {
+                client.reply_err(format!("Error. No such user: {}", to))
+            }
+            Err(error) => {
+                client.reply_err("Error. Failed to look up user account.")?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Invoke the who command.
+    ///
+    /// Every logged-in user is listed once with the current rooms of all of
+    /// their sessions.
+    ///
+    /// This command can only be called when logged in.
+    fn cmd_who(&self, client: &Client<L>) -> MyResult<()> {
+        // The client being serviced is not in `self.clients`
+        let mut users = BTreeMap::<&str, BTreeSet<&str>>::new();
+        for c in self.clients.values().chain(iter::once(client)) {
+            if let Some(user) = c.username() {
+                let rooms = users.entry(user).or_default();
+                rooms.extend(c.current_room());
+            }
+        }
+
+        let list = users
+            .iter()
+            .map(|(user, rooms)| {
+                if rooms.is_empty() {
+                    format!("  {}", user)
+                } else {
+                    let rooms = rooms.iter().copied().collect::<Vec<_>>();
+                    format!("  {} ({})", user, rooms.join(", "))
+                }
+            })
+            .collect::<Vec<_>>();
+        client.reply_ok(format!("Online:\n{}", list.join("\n")))
+    }
+
+    /// Invoke the history command.
+    ///
+    /// The recent messages are pushed before the reply, which tells how many
+    /// there were. At most `HISTORY_SIZE` messages are kept, and `count`
+    /// defaults to the number replayed after login.
+    ///
+    /// This command can only be called when logged in.
+    fn cmd_history(
+        &self,
+        client: &Client<L>,
+        count: Option<u32>,
+    ) -> MyResult<()> {
+        if !client.accepts_pushes(Feature::History) {
+            return client
+                .reply_err("Denied. History requires the history feature.");
+        }
+
+        let count = count.map_or(self.config.history_replay, |n| n as usize);
+        match self.replay_history(client, count) {
+            0 => client.reply_ok("No messages."),
+            1 => client.reply_ok("End of history (1 message)."),
+            n => client.reply_ok(format!("End of history ({} messages).", n)),
+        }
+    }
+
+    //==================================================
+    // Utilities
+    //==================================================
+
+    /// Queue a private message from `user` to `to`, who could not receive it
+    /// right away, and reply to `client` whether it was queued.
+    ///
+    /// `online` tells whether `to` is logged in, just not in a session that
+    /// could receive the message, so that the reply doesn't claim they are
+    /// offline.
+    fn post_letter(
+        &mut self,
+        client: &Client<L>,
+        user: &str,
+        to: &str,
+        text: &str,
+        online: bool,
+    ) -> MyResult<()> {
+        match self.mailbox.post(to, &Letter::now(user, text)) {
+            Ok(true) => {
+                debug!(from = user, to, "queued direct message");
+                if self.config.chat_log_dms {
+                    self.log_event(Event::Direct {
+                        from: user.to_string(),
+                        to: to.to_string(),
+                        text: text.to_string(),
+                    });
+                }
+                let why = if online {
+                    "can't receive private messages right now"
+                } else {
+                    "is offline"
+                };
+                client.reply_ok(format!(
+                    "-> {}: {} ({} {}, the message will be delivered on \
+                     login)",
+                    to, text, to, why
+                ))
+            }
+            Ok(false) => client
+                .reply_err(format!("Denied. The mailbox of {} is full.", to)),
+            Err(error) => {
+                client.reply_err("Error. Failed to queue message.")?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Push `letters`, the unread messages of `user`, to `client`.
+    ///
+    /// The letters are removed from the mailbox once they have all been
+    /// written to the client's socket (see `remove_delivered_letters()`).
+    fn deliver_letters(
+        &self,
+        client: &mut Client<L>,
+        user: &str,
+        letters: Vec<Letter>,
+    ) -> MyResult<()> {
+        if letters.is_empty() {
+            return Ok(());
+        }
+        let n_letters = letters.len();
+        for letter in letters {
+            client.send_msg(
+                &Push::Unread {
+                    time: letter.time,
+                    from: letter.from,
+                    text: letter.text,
+                }
+                .into(),
+            )?;
+        }
+        client.delivered = Some((user.to_string(), n_letters));
+        self.remove_delivered_letters(client);
+        Ok(())
+    }
+
+    /// Remove the letters pushed to `client` on login from the mailbox, if
+    /// they have been written to its socket by now.
+    ///
+    /// Until then they are kept, so that they are delivered again on the next
+    /// login if the client goes away before it could take them. Letters
+    /// posted since are kept either way.
+    fn remove_delivered_letters(&self, client: &mut Client<L>) {
+        if client.sock.queued_len() > 0 {
+            return;
+        }
+        if let Some((user, n_letters)) = client.delivered.take() {
+            if let Err(error) = self.mailbox.remove(&user, n_letters) {
+                info!(
+                    parent: &client.span,
+                    %error,
+                    "failed to remove delivered letters from mailbox"
+                );
+            }
+        }
+    }
+
+    /// Log `client` out, leaving every room, and tell everyone else. The
+    /// username is returned if the client was logged in, otherwise `None`.
+    fn end_session(&mut self, client: &mut Client<L>) -> Option<String> {
+        let user = client.username()?.to_string();
+        for room in &client.rooms {
+            self.log_event(Event::Leave {
+                room: room.clone(),
+                user: user.clone(),
+            });
+        }
+        client.logout();
+        self.broadcast(Push::Logout { user: user.clone() });
+        Some(user)
+    }
+
+    /// Append `event` to the chat log, if there is one.
+    #[inline]
+    fn log_event(&mut self, event: Event) {
+        self.log_record(&Record::now(event));
+    }
+
+    /// Append `record` to the chat log, if there is one.
+    ///
+    /// Failing to write the log is not fatal, the chat goes on without it.
+    fn log_record(&mut self, record: &Record) {
+        if let Some(log) = &mut self.chat_log {
+            if let Err(error) = log.append(record) {
+                info!(%error, "failed to write chat log");
+            }
+        }
+    }
+
+    /// Push the last `n` messages sent to the rooms `client` is a member of
+    /// and return how many there were.
+    ///
+    /// Nothing is pushed if the client did not negotiate history pushes.
+    fn replay_history(&self, client: &Client<L>, n: usize) -> usize {
+        if n == 0 || !client.accepts_pushes(Feature::History) {
+            return 0;
+        }
+
+        let entries =
+            self.history.recent(n, |e| client.rooms.contains(&e.room));
+        for entry in &entries {
+            let msg = ServerMsg::Push(entry.to_push());
+            if let Err(error) = client.send_msg(&msg) {
+                info!(%error, "failed to replay history");
+                return 0;
+            }
+        }
+        entries.len()
+    }
+
+    /// Push `push` to every logged-in client.
+    ///
+    /// See `broadcast_where()`.
+    fn broadcast(&self, push: Push) {
+        self.broadcast_where(push, |_| true);
+    }
+
+    /// Push `push` to every logged-in client that is a member of `room`.
+    ///
+    /// See `broadcast_where()`.
+    fn broadcast_room(&self, room: &str, push: Push) {
+        self.broadcast_where(push, |c| c.rooms.iter().any(|r| r == room));
+    }
+
+    /// Push `push` to every logged-in client that negotiated this kind of
+    /// push (see `Push::feature()`) and for which `pred` returns true, and
+    /// return the number of clients it was pushed to.
+    ///
+    /// The client currently being serviced is not in `self.clients` (see
+    /// `service_client()`) and so does not receive the push. A client that
+    /// fails to receive the push is not dropped here, since the push stays
+    /// queued and the main loop drops the client once writing it fails again
+    /// or too much is queued.
+    fn broadcast_where(
+        &self,
+        push: Push,
+        pred: impl Fn(&Client<L>) -> bool,
+    ) -> usize {
+        let feature = push.feature();
+        let msg = ServerMsg::Push(push);
+        let mut n_clients = 0;
+        for client in self.clients.values() {
+            if !client.is_logged_in()
+                || !client.accepts_pushes(feature)
+                || !pred(client)
+            {
+                continue;
+            }
+            match client.send_msg(&msg) {
+                Ok(()) => n_clients += 1,
+                Err(error) => {
+                    info!(parent: &client.span, %error, "failed to push message");
+                }
+            }
         }
+        n_clients
     }
 }
 
+/// The protocol parameters agreed on during the handshake with a client.
+struct Negotiated {
+    version: u32,
+    features: Vec<Feature>,
+}
+
+/// The state of a client's session, which decides the commands it may run.
+#[derive(Debug, PartialEq, Eq)]
+enum Session {
+    /// Connected, but the hello has not been received yet. Nothing but a
+    /// hello is accepted.
+    Handshake,
+    /// Handshake complete. Only `newuser` and `login` are accepted.
+    LoggedOut,
+    /// Logged in as the contained user. Every command but `newuser` and
+    /// `login` is accepted, i.e. `logout`, `send`, `join`, `leave`, `rooms`,
+    /// `msg`, `who` and `history`.
+    LoggedIn(String),
+}
+
 /// Represent a client.
 ///
-/// This type contains the open socket for the client and the client's username,
-/// if logged in.
-struct Client {
-    sock: ServerSocket,
-    username: Option<String>,
+/// This type contains the open socket for the client, with the output queued
+/// for it, and its address, the protocol parameters once the handshake is
+/// complete, the state of the client's session, the rooms it is a member of,
+/// and the span its log messages belong to.
+struct Client<S: SocketCommon> {
+    sock: S,
+    addr: SockAddr,
+    negotiated: Option<Negotiated>,
+    state: Session,
+    /// The rooms this client has joined, in the order they were joined or
+    /// switched to. The last one is the current room.
+    rooms: Vec<String>,
+    /// Tags log messages about this client with its socket, its address and
+    /// the user it is logged in as (see `client_span()`).
+    span: Span,
+    /// The user whose unread letters were pushed to this client on login and
+    /// how many, until they have been written to the socket and removed from
+    /// the mailbox.
+    delivered: Option<(String, usize)>,
+    /// When the connection was accepted, which limits how long the handshake
+    /// may take.
+    connected_at: Instant,
 }
 
-impl Client {
+impl<S: SocketCommon> Client<S> {
     #[inline]
-    fn new(sock: ServerSocket) -> Self {
+    fn new(sock: S, addr: SockAddr) -> Self {
+        let span = client_span(sock.fd(), &addr, None);
         Self {
             sock,
-            username: None,
+            addr,
+            negotiated: None,
+            state: Session::Handshake,
+            rooms: Vec::new(),
+            span,
+            delivered: None,
+            connected_at: Instant::now(),
+        }
+    }
+
+    /// Return whether `feature` was enabled during the handshake.
+    #[inline]
+    fn has_feature(&self, feature: Feature) -> bool {
+        self.negotiated
+            .as_ref()
+            .is_some_and(|n| n.features.contains(&feature))
+    }
+
+    /// Return whether pushes that require `feature` (see `Push::feature()`)
+    /// may be sent to this client, i.e. whether it negotiated both pushes and
+    /// `feature`.
+    #[inline]
+    fn accepts_pushes(&self, feature: Feature) -> bool {
+        self.has_feature(Feature::Push) && self.has_feature(feature)
+    }
+
+    /// Return the name of the user this client is logged in as, if any.
+    #[inline]
+    fn username(&self) -> Option<&str> {
+        match &self.state {
+            Session::LoggedIn(user) => Some(user),
+            _ => None,
         }
     }
 
-    /// Update this client's state to be logged in.
+    /// Return whether this client is logged in.
+    #[inline]
+    fn is_logged_in(&self) -> bool {
+        matches!(self.state, Session::LoggedIn(_))
+    }
+
+    /// Update this client's state to be logged in, as a member of only the
+    /// default room.
     #[inline]
     fn login(&mut self, user: impl AsRef<str>) {
-        self.username = Some(user.as_ref().to_string());
+        let user = user.as_ref();
+        self.state = Session::LoggedIn(user.to_string());
+        self.rooms = vec![DEFAULT_ROOM.to_string()];
+        self.span = client_span(self.sock.fd(), &self.addr, Some(user));
     }
 
-    /// Update this client's state to be logged out.
+    /// Update this client's state to be logged out, leaving every room.
     ///
     /// The username is returned if this client was logged in, otherwise `None`.
     #[inline]
     fn logout(&mut self) -> Option<String> {
-        self.username.take()
+        self.rooms.clear();
+        match std::mem::replace(&mut self.state, Session::LoggedOut) {
+            Session::LoggedIn(user) => {
+                self.span = client_span(self.sock.fd(), &self.addr, None);
+                Some(user)
+            }
+            state => {
+                self.state = state;
+                None
+            }
+        }
+    }
+
+    /// Return the room that sent messages go to, if this client is in any.
+    #[inline]
+    fn current_room(&self) -> Option<&str> {
+        self.rooms.last().map(String::as_str)
+    }
+
+    /// Make `room` the current room and return whether it was newly joined.
+    fn join_room(&mut self, room: &str) -> bool {
+        let joined = !self.leave_room(room);
+        self.rooms.push(room.to_string());
+        joined
+    }
+
+    /// Leave `room` and return whether this client was a member of it.
+    fn leave_room(&mut self, room: &str) -> bool {
+        let n_rooms = self.rooms.len();
+        self.rooms.retain(|r| r != room);
+        self.rooms.len() != n_rooms
+    }
+
+    /// Send `msg` to this client, in the format of the negotiated protocol
+    /// version.
+    ///
+    /// This never blocks: whatever the socket doesn't take right away is
+    /// queued and written by the main loop once the client reads again.
+    #[inline]
+    fn send_msg(&self, msg: &ServerMsg) -> MyResult<()> {
+        let version = self
+            .negotiated
+            .as_ref()
+            .map_or(PROTOCOL_VERSION, |n| n.version);
+        self.sock.queue(msg.encode_version(version))
     }
 
     /// Send an ok reply to this client.
     #[inline]
     fn reply_ok(&self, msg: impl AsRef<str>) -> MyResult<()> {
-        self.sock.send(msg)
+        self.send_msg(&Reply::Ok(msg.as_ref().to_string()).into())
     }
 
-    /// Send an error reply to this client with the correct first byte,
-    /// `REPLY_FLAG_ERR`.
+    /// Send an error reply to this client.
     #[inline]
     fn reply_err(&self, msg: impl AsRef<str>) -> MyResult<()> {
-        self.sock
-            .send(format!("{}{}", REPLY_FLAG_ERR as char, msg.as_ref()))
+        self.send_msg(&Reply::Err(msg.as_ref().to_string()).into())
+    }
+}
+
+/// Create the span of the client with socket `sock` and address `addr` that
+/// is logged in as `user`, if any.
+///
+/// A new span is created whenever the user changes, rather than recording the
+/// user on the existing span, because recording a field again appends it to
+/// the formatted span instead of replacing it. Client spans have no parent,
+/// even when created while another client's span is entered.
+fn client_span(sock: c_int, addr: &SockAddr, user: Option<&str>) -> Span {
+    let span = info_span!(
+        parent: None,
+        "client",
+        sock,
+        peer = %addr,
+        user = field::Empty
+    );
+    if let Some(user) = user {
+        span.record("user", field::display(user));
     }
+    span
 }
@@ -0,0 +1,85 @@
+use std::str::FromStr;
+
+use crate::err::MyResult;
+
+/// Return the value given on the command line (`cli`), or else the parsed
+/// environment variable `key`, or else the value from the config file
+/// (`file`), or `None` if none of them is set.
+pub fn pick<T: FromStr>(
+    cli: Option<T>,
+    key: &str,
+    file: Option<T>,
+) -> MyResult<Option<T>> {
+    if cli.is_some() {
+        return Ok(cli);
+    }
+    Ok(var_opt(key)?.or(file))
+}
+
+/// Parse the environment variable `key`, or return `None` if it is not set.
+pub fn var_opt<T: FromStr>(key: &str) -> MyResult<Option<T>> {
+    match dotenv::var(key) {
+        Ok(val) => {
+            Ok(Some(val.parse().map_err(|_| {
+                format!("{} has an invalid value: {}", key, val)
+            })?))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parse the value of the config file key `key`, if it is set.
+pub fn file_parse<T: FromStr>(
+    key: &str,
+    val: Option<String>,
+) -> MyResult<Option<T>> {
+    val.map(|val| {
+        val.parse().map_err(|_| {
+            format!("config file key {} has an invalid value: {}", key, val)
+                .into()
+        })
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn pick_precedence() {
+        let key = "LIBCHAT_TEST_PICK_PRECEDENCE";
+        env::remove_var(key);
+        assert_eq!(pick::<u16>(None, key, None).unwrap(), None);
+        assert_eq!(pick(None, key, Some(1_u16)).unwrap(), Some(1));
+
+        env::set_var(key, "2");
+        assert_eq!(pick(None, key, Some(1_u16)).unwrap(), Some(2));
+        assert_eq!(pick(Some(3_u16), key, Some(1)).unwrap(), Some(3));
+        env::remove_var(key);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let key = "LIBCHAT_TEST_INVALID_VALUES";
+        env::set_var(key, "many");
+        let err = var_opt::<u16>(key).unwrap_err().to_string();
+        assert!(err.contains("LIBCHAT_TEST_INVALID_VALUES"), "{}", err);
+        assert!(pick::<u16>(None, key, None).is_err());
+        // The environment is only parsed if the command line sets nothing
+        assert_eq!(pick(Some(3_u16), key, None).unwrap(), Some(3));
+        env::remove_var(key);
+
+        assert_eq!(file_parse::<u16>("port", None).unwrap(), None);
+        assert_eq!(
+            file_parse::<u16>("port", Some("1".into())).unwrap(),
+            Some(1)
+        );
+        let err = file_parse::<u16>("port", Some("x".into()))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("config file key port"), "{}", err);
+    }
+}
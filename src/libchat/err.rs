@@ -4,6 +4,8 @@ use dotenv;
 use regex;
 use thiserror::Error;
 
+use crate::protocol::RejectReason;
+
 pub type MyResult<T> = Result<T, MyError>;
 
 /// My error type.
@@ -33,8 +35,24 @@ pub enum MyError {
     #[error("dotenv: {0}")]
     Dotenv(#[from] dotenv::Error),
 
-    #[error("server rejected the connection")]
-    ClientRejected,
+    #[error("password hash: {0}")]
+    PasswordHash(#[from] password_hash::Error),
+
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("server rejected the connection ({reason}): {message}")]
+    ClientRejected {
+        reason: RejectReason,
+        message: String,
+    },
+
+    #[error("connection closed by peer")]
+    ConnectionClosed,
+
+    #[error("{0}")]
+    Protocol(String),
 }
 
 impl From<String> for MyError {
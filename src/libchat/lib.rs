@@ -1,21 +1,40 @@
-use std::os::raw::c_int;
+use std::{os::raw::c_int, time::Duration};
 
 use libc::size_t;
 
 mod banner;
 pub use banner::*;
 
+pub mod config;
+
 pub mod err;
 
+pub mod logging;
+
+pub mod password;
+
+pub mod protocol;
+
 mod signal;
 pub use signal::*;
 
 pub mod sys;
 
+mod user_store;
+pub use user_store::*;
+
 mod users_dao;
 pub use users_dao::UsersDao;
 
-/// Port used by servers and clients
+mod validate;
+pub use validate::*;
+
+#[cfg(feature = "sqlite")]
+mod sqlite_users_dao;
+#[cfg(feature = "sqlite")]
+pub use sqlite_users_dao::SqliteUsersDao;
+
+/// Default port used by servers and clients, see `CHAT_PORT` in the README.
 pub const CHAT_PORT: u16 = 10087;
 
 // Maximum number of pending connections that can be in the queue.
@@ -33,39 +52,30 @@ pub const PASSWORD_MIN: usize = 4;
 /// Maximum length of username.
 pub const PASSWORD_MAX: usize = 8;
 
-/// Maximum length of a message that can be sent, *not* including a terminating
-/// null byte.
-pub const MSG_MAX: size_t = 256;
-
-/// The maximum buffer size of a client command or a server reply.
-///
-/// The maximum message that can be sent is the reply of a send command from
-/// the server, which is `USER: MSG`. Therefore, the largest size of a message,
-/// and buffers that will hold a message, is: the maximum username length + 2
-/// for the `": "` after the username + the maximum message size + a terminating
-/// null byte.
-pub const COMMAND_MAX: usize = USERNAME_MAX + 2 + MSG_MAX + 1;
+/// Maximum length of a room name, not counting the leading `#`.
+pub const ROOM_MAX: usize = 32;
 
-/// The character to use to separate server command arguments.
-pub const COMMAND_SEP: &str = "\x02";
+/// The room every user joins when logging in.
+pub const DEFAULT_ROOM: &str = "#general";
 
-/// Represent a server reply.
-///
-/// - An `Ok` represents a command that completed successfully.
-/// - An `Err` represents a command that failed.
-///
-/// The reply will be sent to the client with the first byte being either
-/// `RESPONSE_FLAG_OK` or `RESPONSE_FLAG_ERR`.
-pub type ServerReply = Result<String, String>;
+/// Maximum length of a message that can be sent.
+pub const MSG_MAX: size_t = 256;
 
-/// Magic number byte for handshake between client and server indicating that
-/// the connection is accepted.
+/// The maximum size in bytes of the payload of one frame sent over a socket,
+/// i.e. the maximum size of a client command or a server reply or push.
 ///
-/// This server must reply with this exact message after connecting, or the
-/// program will exit.
-pub const HANDSHAKE_ACK: &str = "\x06";
+/// Every message is sent as a frame, which is a header containing the payload
+/// length as a big-endian `u32` followed by the payload itself. A frame that
+/// claims to be larger than this is treated as a protocol error.
+pub const FRAME_MAX: usize = 64 * 1024;
 
-/// Magic number byte for server command replies indicating a failure.
+/// Maximum number of bytes the server queues for sending to one client.
 ///
-/// This must be the first byte of the reply string.
-pub const REPLY_FLAG_ERR: u8 = 0x15;
+/// A client that stops reading has its output queued rather than stalling the
+/// server, and is disconnected once the queue grows past this.
+pub const SEND_QUEUE_MAX: usize = 1024 * 1024;
+
+/// How long the server waits for a new connection to complete the handshake
+/// before closing it, so that connections that never send a hello don't stay
+/// open forever.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
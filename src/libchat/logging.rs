@@ -0,0 +1,117 @@
+use std::{fmt, fs::OpenOptions, io, path::Path, str::FromStr, sync::Arc};
+
+use serde::{Serialize, Serializer};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+use crate::err::MyResult;
+
+/// Which log messages are printed, in the syntax of `RUST_LOG`.
+///
+/// This is either a level (`off`, `error`, `warn`, `info`, `debug` or
+/// `trace`), or a comma separated list of directives that set the level of
+/// specific modules or spans, e.g. `warn,chat_server=debug` or
+/// `info,[client{user=alice}]=trace`. Levels more verbose than the one
+/// compiled in (see the `quiet` feature) are never printed.
+///
+/// The filter is checked when it is parsed, and kept as text so that it can
+/// be printed back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFilter(String);
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EnvFilter::try_new(s)
+            .map_err(|err| format!("invalid log filter: {}: {}", s, err))?;
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for LogFilter {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+/// How log lines are formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event, with every field.
+    Full,
+    /// One shorter line per event.
+    Compact,
+    /// Several lines per event, for reading during development.
+    Pretty,
+    /// One JSON object per line, for log shippers. The fields of the event
+    /// are flattened into the object, and the spans it happened in are listed
+    /// under `spans`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "compact" => Ok(Self::Compact),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Install the global subscriber that prints log messages.
+///
+/// Messages that pass `filter` are formatted as `format` and written to
+/// standard output, or appended to the file at `file` if given. Colors are
+/// only used if `ansi` is true and the messages go to standard output.
+pub fn init_logging(
+    filter: &LogFilter,
+    format: LogFormat,
+    file: Option<&Path>,
+    ansi: bool,
+) -> MyResult<()> {
+    let writer = match file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| {
+                    format!(
+                        "failed to open log file: {}: {}",
+                        path.display(),
+                        err
+                    )
+                })?;
+            BoxMakeWriter::new(Arc::new(file))
+        }
+        None => BoxMakeWriter::new(io::stdout),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_target(false)
+        .with_ansi(ansi && file.is_none())
+        .with_env_filter(EnvFilter::new(&filter.0))
+        .with_writer(writer);
+    let res = match format {
+        LogFormat::Full => builder.try_init(),
+        LogFormat::Compact => builder.compact().try_init(),
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+    res.map_err(|err| format!("failed to set up logging: {}", err).into())
+}
@@ -0,0 +1,37 @@
+use argon2::Argon2;
+use password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier,
+    SaltString,
+};
+
+use crate::err::MyResult;
+
+/// Return whether `stored` is a password hash rather than a plaintext
+/// password.
+///
+/// Hashes are stored as PHC strings, which always start with `$`.
+#[inline]
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with('$') && PasswordHash::new(stored).is_ok()
+}
+
+/// Hash `pass` with a new random salt and return the hash as a PHC string,
+/// e.g. `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+pub fn hash_password(pass: &str) -> MyResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(pass.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Return whether `pass` matches the PHC string `hash`.
+///
+/// The comparison is done in constant time. An invalid hash never matches.
+pub fn verify_password(pass: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(pass.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
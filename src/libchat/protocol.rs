@@ -0,0 +1,1013 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    err::{MyError, MyResult},
+    DEFAULT_ROOM,
+};
+
+/// The newest protocol version spoken by this library.
+///
+/// Version 2 added rooms, which changed the format of pushed messages.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest protocol version that this library can still speak.
+///
+/// Version 1 clients are sent messages in the format without rooms (see
+/// `ServerMsg::encode_version()`).
+pub const PROTOCOL_VERSION_MIN: u32 = 1;
+
+/// The byte used to separate the fields of a message.
+///
+/// The payload of every frame is a list of fields separated by this byte. A
+/// client command is the command name followed by its arguments. A server
+/// message starts with a flag byte (`REPLY_FLAG_OK`, `REPLY_FLAG_ERR` or
+/// `PUSH_FLAG`) followed by its fields.
+pub const FIELD_SEP: char = '\x02';
+
+/// The byte used to escape a `FIELD_SEP` or `FIELD_ESC` inside a field, so
+/// that fields may contain arbitrary text.
+pub const FIELD_ESC: char = '\x10';
+
+/// Magic number byte for handshake between client and server indicating that
+/// the connection is accepted.
+///
+/// This must be the first byte of the server's reply to a `Hello`.
+pub const HANDSHAKE_ACK: char = '\x06';
+
+/// Magic number byte for handshake between client and server indicating that
+/// the connection is rejected.
+///
+/// This must be the first byte of the server's reply to a `Hello`.
+pub const HANDSHAKE_NAK: char = '\x15';
+
+/// Magic number byte for server command replies indicating a success.
+///
+/// This must be the first byte of the reply string.
+pub const REPLY_FLAG_OK: char = '\x06';
+
+/// Magic number byte for server command replies indicating a failure.
+///
+/// This must be the first byte of the reply string.
+pub const REPLY_FLAG_ERR: char = '\x15';
+
+/// Magic number byte for unsolicited messages pushed by the server, e.g. a
+/// message broadcast by another user.
+///
+/// This must be the first byte of the push string. It distinguishes a push
+/// from the reply to a command, which the client may be waiting on at the same
+/// time.
+pub const PUSH_FLAG: char = '\x07';
+
+//==============================================================================
+// Fields
+//==============================================================================
+
+/// Join `fields` with `FIELD_SEP`, escaping special bytes in each field.
+pub fn encode_fields<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut out = String::new();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(FIELD_SEP);
+        }
+        for c in field.as_ref().chars() {
+            if c == FIELD_SEP || c == FIELD_ESC {
+                out.push(FIELD_ESC);
+            }
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Split `msg` on unescaped `FIELD_SEP`s and remove the escapes.
+///
+/// There is always at least one field, even if `msg` is empty.
+pub fn decode_fields(msg: &str) -> MyResult<Vec<String>> {
+    let mut fields = vec![String::new()];
+    let mut chars = msg.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            FIELD_ESC => match chars.next() {
+                Some(escaped) => fields.last_mut().unwrap().push(escaped),
+                None => {
+                    return Err(MyError::Protocol(
+                        "message ends with an escape byte".to_string(),
+                    ))
+                }
+            },
+            FIELD_SEP => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    Ok(fields)
+}
+
+//==============================================================================
+// Handshake
+//==============================================================================
+
+/// An optional protocol feature that is negotiated during the handshake.
+///
+/// Unknown feature names are ignored when decoding, so that newer peers can
+/// announce features that older peers don't know about.
+///
+/// Every kind of push other than room messages has its own feature, so that
+/// the server only sends a client the kinds of pushes it knows how to decode
+/// (see `Push::feature()`). They are only used along with `Push`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// The server may push unsolicited messages to the client, starting with
+    /// messages sent to its rooms (`Push::Message`).
+    Push,
+    /// Private messages are pushed (`Push::Direct`).
+    Direct,
+    /// Users logging in and out are announced (`Push::Login` and
+    /// `Push::Logout`).
+    Presence,
+    /// Messages from the history are replayed (`Push::History`).
+    History,
+    /// Private messages sent while the user was offline are delivered on login
+    /// (`Push::Unread`).
+    Unread,
+    /// The server announces that it is shutting down (`Push::Shutdown`).
+    Shutdown,
+}
+
+impl Feature {
+    /// Every feature supported by this library.
+    pub const ALL: &'static [Feature] = &[
+        Feature::Push,
+        Feature::Direct,
+        Feature::Presence,
+        Feature::History,
+        Feature::Unread,
+        Feature::Shutdown,
+    ];
+
+    /// Return the name of this feature as it appears on the wire.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Push => "push",
+            Self::Direct => "dm",
+            Self::Presence => "presence",
+            Self::History => "history",
+            Self::Unread => "unread",
+            Self::Shutdown => "shutdown",
+        }
+    }
+
+    /// Return the feature called `name`, if it is known.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|f| f.name() == name)
+    }
+}
+
+impl Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parse a version number field.
+fn decode_version(field: &str) -> MyResult<u32> {
+    field
+        .parse()
+        .map_err(|_| MyError::Protocol(format!("invalid version: {:?}", field)))
+}
+
+/// Parse a time field, in seconds since the Unix epoch.
+fn decode_time(field: &str) -> MyResult<i64> {
+    field
+        .parse()
+        .map_err(|_| MyError::Protocol(format!("invalid time: {:?}", field)))
+}
+
+/// The reason the server gives for rejecting a connection.
+///
+/// This is sent as a numeric code alongside a human readable message, so that
+/// a client can decide what to do without parsing the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// A reason not known to this version of the library.
+    Other,
+    /// The server has reached its maximum number of clients.
+    ServerFull,
+    /// The client's address is not allowed to connect.
+    Banned,
+    /// The server is shutting down and not accepting new connections.
+    ShuttingDown,
+    /// The client and server have no protocol version in common.
+    VersionMismatch,
+    /// The client did not send a valid hello message.
+    BadHandshake,
+}
+
+impl RejectReason {
+    /// Return the code of this reason as it appears on the wire.
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Other => 0,
+            Self::ServerFull => 1,
+            Self::Banned => 2,
+            Self::ShuttingDown => 3,
+            Self::VersionMismatch => 4,
+            Self::BadHandshake => 5,
+        }
+    }
+
+    /// Return the reason with the given code, or `Other` if it is unknown.
+    pub fn from_code(code: u32) -> Self {
+        match code {
+            1 => Self::ServerFull,
+            2 => Self::Banned,
+            3 => Self::ShuttingDown,
+            4 => Self::VersionMismatch,
+            5 => Self::BadHandshake,
+            _ => Self::Other,
+        }
+    }
+
+    /// Return whether the same connection attempt may succeed later.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::ServerFull | Self::ShuttingDown)
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Other => "other",
+            Self::ServerFull => "server full",
+            Self::Banned => "banned",
+            Self::ShuttingDown => "shutting down",
+            Self::VersionMismatch => "version mismatch",
+            Self::BadHandshake => "bad handshake",
+        })
+    }
+}
+
+/// The first message sent by a client after connecting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// The newest protocol version the client speaks.
+    pub version: u32,
+    /// The features the client supports.
+    pub features: Vec<Feature>,
+}
+
+impl Hello {
+    /// Encode this message for sending to the server.
+    pub fn encode(&self) -> String {
+        let mut fields = vec!["hello".to_string(), self.version.to_string()];
+        fields.extend(self.features.iter().map(|f| f.name().to_string()));
+        encode_fields(&fields)
+    }
+
+    /// Decode a hello message received from a client.
+    pub fn decode(msg: &str) -> MyResult<Self> {
+        let fields = decode_fields(msg)?;
+        match fields.as_slice() {
+            [kind, version, features @ ..] if kind == "hello" => Ok(Self {
+                version: decode_version(version)?,
+                features: features
+                    .iter()
+                    .filter_map(|f| Feature::from_name(f))
+                    .collect(),
+            }),
+            _ => Err(MyError::Protocol(
+                "expected hello message from client".to_string(),
+            )),
+        }
+    }
+}
+
+/// The server's reply to a `Hello`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeReply {
+    /// The connection is accepted.
+    Welcome {
+        /// The protocol version to use for the rest of the connection.
+        version: u32,
+        /// The name of the server.
+        server_name: String,
+        /// The features enabled for this connection, which is a subset of
+        /// those announced by the client.
+        features: Vec<Feature>,
+    },
+    /// The connection is rejected and will be closed by the server.
+    Reject {
+        reason: RejectReason,
+        message: String,
+    },
+}
+
+impl HandshakeReply {
+    /// Encode this message for sending to a client.
+    pub fn encode(&self) -> String {
+        match self {
+            Self::Welcome {
+                version,
+                server_name,
+                features,
+            } => {
+                let mut fields = vec![version.to_string(), server_name.clone()];
+                fields.extend(features.iter().map(|f| f.name().to_string()));
+                format!("{}{}", HANDSHAKE_ACK, encode_fields(&fields))
+            }
+            Self::Reject { reason, message } => format!(
+                "{}{}",
+                HANDSHAKE_NAK,
+                encode_fields(&[&reason.code().to_string(), message])
+            ),
+        }
+    }
+
+    /// Decode a handshake reply received from the server.
+    pub fn decode(msg: &str) -> MyResult<Self> {
+        let mut chars = msg.chars();
+        let flag = chars.next();
+        let fields = decode_fields(chars.as_str())?;
+
+        match (flag, fields.as_slice()) {
+            (Some(HANDSHAKE_ACK), [version, server_name, features @ ..]) => {
+                Ok(Self::Welcome {
+                    version: decode_version(version)?,
+                    server_name: server_name.clone(),
+                    features: features
+                        .iter()
+                        .filter_map(|f| Feature::from_name(f))
+                        .collect(),
+                })
+            }
+            (Some(HANDSHAKE_NAK), [code, message]) => Ok(Self::Reject {
+                reason: RejectReason::from_code(code.parse().unwrap_or(0)),
+                message: message.clone(),
+            }),
+            _ => Err(MyError::Protocol(format!(
+                "invalid handshake reply from server: {:?}",
+                msg
+            ))),
+        }
+    }
+}
+
+//==============================================================================
+// Commands
+//==============================================================================
+
+/// A command sent from a client to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Create a new user account.
+    NewUser { user: String, pass: String },
+    /// Login to the chat server.
+    Login { user: String, pass: String },
+    /// Logout of the chat server.
+    Logout,
+    /// Broadcast a message to everyone in the current room.
+    Send { msg: String },
+    /// Join a room, or switch to it if already joined, making it the current
+    /// room.
+    Join { room: String },
+    /// Leave a room.
+    Leave { room: String },
+    /// List the rooms that have members.
+    Rooms,
+    /// Send a private message to every session of one user.
+    Msg { to: String, text: String },
+    /// List the logged-in users and their current rooms.
+    Who,
+    /// Replay recent messages from the rooms the user is a member of, at most
+    /// `count` of them if given.
+    History { count: Option<u32> },
+}
+
+impl Command {
+    /// Return the name of this command as it appears on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NewUser { .. } => "newuser",
+            Self::Login { .. } => "login",
+            Self::Logout => "logout",
+            Self::Send { .. } => "send",
+            Self::Join { .. } => "join",
+            Self::Leave { .. } => "leave",
+            Self::Rooms => "rooms",
+            Self::Msg { .. } => "msg",
+            Self::Who => "who",
+            Self::History { .. } => "history",
+        }
+    }
+
+    /// Encode this command for sending to the server.
+    pub fn encode(&self) -> String {
+        let name = self.name();
+        match self {
+            Self::NewUser { user, pass } | Self::Login { user, pass } => {
+                encode_fields(&[name, user.as_str(), pass.as_str()])
+            }
+            Self::Logout | Self::Rooms | Self::Who => encode_fields(&[name]),
+            Self::Send { msg } => encode_fields(&[name, msg.as_str()]),
+            Self::Join { room } | Self::Leave { room } => {
+                encode_fields(&[name, room.as_str()])
+            }
+            Self::Msg { to, text } => {
+                encode_fields(&[name, to.as_str(), text.as_str()])
+            }
+            Self::History { count: None } => encode_fields(&[name]),
+            Self::History { count: Some(n) } => {
+                encode_fields(&[name, n.to_string().as_str()])
+            }
+        }
+    }
+
+    /// Decode a command received from a client.
+    ///
+    /// The error message is suitable for sending back to the client as an
+    /// error reply.
+    pub fn decode(msg: &str) -> MyResult<Self> {
+        let fields = decode_fields(msg)?;
+        let (name, args) = fields.split_first().unwrap();
+
+        let invalid_num_args = |expected: usize| {
+            Err(MyError::Protocol(format!(
+                "expected {} arguments but got {}",
+                expected,
+                args.len()
+            )))
+        };
+
+        match (name.as_str(), args) {
+            ("newuser", [user, pass]) => Ok(Self::NewUser {
+                user: user.clone(),
+                pass: pass.clone(),
+            }),
+            ("login", [user, pass]) => Ok(Self::Login {
+                user: user.clone(),
+                pass: pass.clone(),
+            }),
+            ("logout", []) => Ok(Self::Logout),
+            ("send", [msg]) => Ok(Self::Send { msg: msg.clone() }),
+            ("join", [room]) => Ok(Self::Join { room: room.clone() }),
+            ("leave", [room]) => Ok(Self::Leave { room: room.clone() }),
+            ("rooms", []) => Ok(Self::Rooms),
+            ("who", []) => Ok(Self::Who),
+            ("history", []) => Ok(Self::History { count: None }),
+            ("history", [n]) => match n.parse() {
+                Ok(n) => Ok(Self::History { count: Some(n) }),
+                Err(_) => Err(MyError::Protocol(format!(
+                    "Error. Invalid message count: {}",
+                    n
+                ))),
+            },
+            ("msg", [to, text]) => Ok(Self::Msg {
+                to: to.clone(),
+                text: text.clone(),
+            }),
+
+            ("newuser", _) | ("login", _) | ("msg", _) => invalid_num_args(2),
+            ("history", _) => Err(MyError::Protocol(format!(
+                "expected at most 1 argument but got {}",
+                args.len()
+            ))),
+            ("logout", _) | ("rooms", _) | ("who", _) => invalid_num_args(0),
+            ("send", _) | ("join", _) | ("leave", _) => invalid_num_args(1),
+
+            _ => Err(MyError::Protocol(format!(
+                "Error. Command not recognized: {}",
+                name
+            ))),
+        }
+    }
+}
+
+//==============================================================================
+// Server Messages
+//==============================================================================
+
+/// The reply to a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// The command completed successfully.
+    Ok(String),
+    /// The command failed.
+    Err(String),
+}
+
+impl Reply {
+    /// Return whether this reply indicates success.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Self::Ok(_))
+    }
+}
+
+/// The kinds of pushes known to this library, as they appear on the wire.
+const PUSH_KINDS: &[&str] =
+    &["msg", "dm", "hist", "unread", "login", "logout", "shutdown"];
+
+/// An unsolicited message from the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Push {
+    /// A message sent to a room by another user.
+    Message {
+        room: String,
+        from: String,
+        text: String,
+    },
+    /// A private message sent to this user by another user.
+    Direct { from: String, text: String },
+    /// A message that was sent to a room before, replayed from the history.
+    /// `time` is when it was sent, in seconds since the Unix epoch.
+    History {
+        time: i64,
+        room: String,
+        from: String,
+        text: String,
+    },
+    /// A private message that was sent to this user while they were offline.
+    /// `time` is when it was sent, in seconds since the Unix epoch.
+    Unread {
+        time: i64,
+        from: String,
+        text: String,
+    },
+    /// A user logged in.
+    Login { user: String },
+    /// A user logged out or disconnected.
+    Logout { user: String },
+    /// The server is shutting down and will close the connection in `grace`
+    /// seconds, or as soon as it can if `grace` is zero. The server may give
+    /// a `reason`.
+    Shutdown { grace: u32, reason: Option<String> },
+    /// A push of a kind not known to this version of the library, which should
+    /// be ignored. Only the kind is kept.
+    Unknown { kind: String },
+}
+
+impl Push {
+    /// Return the feature a client must have negotiated, besides
+    /// `Feature::Push`, to be sent this push.
+    pub fn feature(&self) -> Feature {
+        match self {
+            Self::Message { .. } | Self::Unknown { .. } => Feature::Push,
+            Self::Direct { .. } => Feature::Direct,
+            Self::History { .. } => Feature::History,
+            Self::Unread { .. } => Feature::Unread,
+            Self::Login { .. } | Self::Logout { .. } => Feature::Presence,
+            Self::Shutdown { .. } => Feature::Shutdown,
+        }
+    }
+}
+
+/// A message sent from the server to a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerMsg {
+    /// The reply to the previous command.
+    Reply(Reply),
+    /// An unsolicited message.
+    Push(Push),
+}
+
+impl From<Reply> for ServerMsg {
+    fn from(reply: Reply) -> Self {
+        Self::Reply(reply)
+    }
+}
+
+impl From<Push> for ServerMsg {
+    fn from(push: Push) -> Self {
+        Self::Push(push)
+    }
+}
+
+impl ServerMsg {
+    /// Encode this message for sending to a client that speaks the newest
+    /// protocol version.
+    #[inline]
+    pub fn encode(&self) -> String {
+        self.encode_version(PROTOCOL_VERSION)
+    }
+
+    /// Encode this message for sending to a client that negotiated protocol
+    /// version `version`.
+    ///
+    /// Version 1 has no rooms, so messages sent to a room are encoded without
+    /// the room. Pushes other than room messages are never sent to version 1
+    /// clients, which don't negotiate the features they require.
+    pub fn encode_version(&self, version: u32) -> String {
+        let (flag, body) = match self {
+            Self::Reply(Reply::Ok(text)) => (REPLY_FLAG_OK, text.clone()),
+            Self::Reply(Reply::Err(text)) => (REPLY_FLAG_ERR, text.clone()),
+            Self::Push(Push::Message { from, text, .. }) if version < 2 => (
+                PUSH_FLAG,
+                encode_fields(&["msg", from.as_str(), text.as_str()]),
+            ),
+            Self::Push(Push::Message { room, from, text }) => (
+                PUSH_FLAG,
+                encode_fields(&[
+                    "msg",
+                    room.as_str(),
+                    from.as_str(),
+                    text.as_str(),
+                ]),
+            ),
+            Self::Push(Push::Direct { from, text }) => (
+                PUSH_FLAG,
+                encode_fields(&["dm", from.as_str(), text.as_str()]),
+            ),
+            Self::Push(Push::History {
+                time,
+                room,
+                from,
+                text,
+            }) => (
+                PUSH_FLAG,
+                encode_fields(&[
+                    "hist",
+                    time.to_string().as_str(),
+                    room.as_str(),
+                    from.as_str(),
+                    text.as_str(),
+                ]),
+            ),
+            Self::Push(Push::Unread { time, from, text }) => (
+                PUSH_FLAG,
+                encode_fields(&[
+                    "unread",
+                    time.to_string().as_str(),
+                    from.as_str(),
+                    text.as_str(),
+                ]),
+            ),
+            Self::Push(Push::Login { user }) => {
+                (PUSH_FLAG, encode_fields(&["login", user.as_str()]))
+            }
+            Self::Push(Push::Logout { user }) => {
+                (PUSH_FLAG, encode_fields(&["logout", user.as_str()]))
+            }
+            // No reason is sent as an empty field
+            Self::Push(Push::Shutdown { grace, reason }) => (
+                PUSH_FLAG,
+                encode_fields(&[
+                    "shutdown",
+                    grace.to_string().as_str(),
+                    reason.as_deref().unwrap_or_default(),
+                ]),
+            ),
+            Self::Push(Push::Unknown { kind }) => {
+                (PUSH_FLAG, encode_fields(&[kind.as_str()]))
+            }
+        };
+        format!("{}{}", flag, body)
+    }
+
+    /// Decode a message received from the server.
+    ///
+    /// A push of an unknown kind is decoded as `Push::Unknown`, so that a
+    /// server may add new kinds of pushes without breaking older clients. A
+    /// known kind with the wrong fields is an error.
+    ///
+    /// Messages from a version 1 server, which has no rooms, are decoded as
+    /// sent to `DEFAULT_ROOM`.
+    pub fn decode(msg: &str) -> MyResult<Self> {
+        let mut chars = msg.chars();
+        let flag = chars.next();
+        let body = chars.as_str();
+
+        match flag {
+            Some(REPLY_FLAG_OK) => Ok(Reply::Ok(body.to_string()).into()),
+            Some(REPLY_FLAG_ERR) => Ok(Reply::Err(body.to_string()).into()),
+            Some(PUSH_FLAG) => {
+                let fields = decode_fields(body)?;
+                match fields.as_slice() {
+                    [kind, room, from, text] if kind == "msg" => {
+                        Ok(Push::Message {
+                            room: room.clone(),
+                            from: from.clone(),
+                            text: text.clone(),
+                        }
+                        .into())
+                    }
+                    [kind, from, text] if kind == "msg" => Ok(Push::Message {
+                        room: DEFAULT_ROOM.to_string(),
+                        from: from.clone(),
+                        text: text.clone(),
+                    }
+                    .into()),
+                    [kind, from, text] if kind == "dm" => Ok(Push::Direct {
+                        from: from.clone(),
+                        text: text.clone(),
+                    }
+                    .into()),
+                    [kind, time, room, from, text] if kind == "hist" => {
+                        Ok(Push::History {
+                            time: decode_time(time)?,
+                            room: room.clone(),
+                            from: from.clone(),
+                            text: text.clone(),
+                        }
+                        .into())
+                    }
+                    [kind, time, from, text] if kind == "unread" => {
+                        Ok(Push::Unread {
+                            time: decode_time(time)?,
+                            from: from.clone(),
+                            text: text.clone(),
+                        }
+                        .into())
+                    }
+                    [kind, user] if kind == "login" => {
+                        Ok(Push::Login { user: user.clone() }.into())
+                    }
+                    [kind, user] if kind == "logout" => {
+                        Ok(Push::Logout { user: user.clone() }.into())
+                    }
+                    [kind, grace, reason] if kind == "shutdown" => {
+                        Ok(Push::Shutdown {
+                            grace: grace.parse().map_err(|_| {
+                                MyError::Protocol(format!(
+                                    "invalid grace period: {:?}",
+                                    grace
+                                ))
+                            })?,
+                            reason: Some(reason.clone())
+                                .filter(|r| !r.is_empty()),
+                        }
+                        .into())
+                    }
+                    [kind, ..] if !PUSH_KINDS.contains(&kind.as_str()) => {
+                        Ok(Push::Unknown { kind: kind.clone() }.into())
+                    }
+                    _ => Err(MyError::Protocol(format!(
+                        "invalid push from server: {:?}",
+                        body
+                    ))),
+                }
+            }
+            _ => Err(MyError::Protocol(format!(
+                "invalid message from server: {:?}",
+                msg
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text containing every byte that must be escaped inside a field.
+    const TRICKY: &str = "a\x02b\x10c\x10\x02d\x02";
+
+    fn protocol_err<T: fmt::Debug>(res: MyResult<T>) -> String {
+        match res {
+            Err(MyError::Protocol(msg)) => msg,
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fields_escape_special_bytes() {
+        assert_eq!(
+            encode_fields(&["a\x02b", "c\x10"]),
+            "a\x10\x02b\x02c\x10\x10"
+        );
+        let fields = ["", TRICKY, "plain", ""];
+        assert_eq!(decode_fields(&encode_fields(&fields)).unwrap(), fields);
+        assert_eq!(decode_fields("").unwrap(), [""]);
+    }
+
+    #[test]
+    fn fields_reject_trailing_escape() {
+        let err = protocol_err(decode_fields("abc\x10"));
+        assert_eq!(err, "message ends with an escape byte");
+        protocol_err(Command::decode("send\x02abc\x10"));
+        protocol_err(ServerMsg::decode("\x07msg\x02a\x02b\x10"));
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            features: Feature::ALL.to_vec(),
+        };
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+
+        let hello = Hello::decode("hello\x021\x02push\x02teleport").unwrap();
+        assert_eq!(hello.version, 1);
+        assert_eq!(hello.features, [Feature::Push]);
+
+        protocol_err(Hello::decode("hello"));
+        protocol_err(Hello::decode("hello\x02two"));
+        protocol_err(Hello::decode("login\x02alice\x02pass"));
+    }
+
+    #[test]
+    fn handshake_reply_round_trip() {
+        let replies = [
+            HandshakeReply::Welcome {
+                version: PROTOCOL_VERSION,
+                server_name: TRICKY.to_string(),
+                features: vec![Feature::Push, Feature::Shutdown],
+            },
+            HandshakeReply::Welcome {
+                version: 1,
+                server_name: String::new(),
+                features: Vec::new(),
+            },
+            HandshakeReply::Reject {
+                reason: RejectReason::ServerFull,
+                message: TRICKY.to_string(),
+            },
+            HandshakeReply::Reject {
+                reason: RejectReason::Other,
+                message: String::new(),
+            },
+        ];
+        for reply in replies {
+            assert_eq!(HandshakeReply::decode(&reply.encode()).unwrap(), reply);
+        }
+
+        let reply = HandshakeReply::decode("\x1599\x02later").unwrap();
+        assert_eq!(
+            reply,
+            HandshakeReply::Reject {
+                reason: RejectReason::Other,
+                message: "later".to_string(),
+            }
+        );
+
+        protocol_err(HandshakeReply::decode(""));
+        protocol_err(HandshakeReply::decode("\x062"));
+        protocol_err(HandshakeReply::decode("\x151"));
+        protocol_err(HandshakeReply::decode("x2\x02name"));
+    }
+
+    #[test]
+    fn reject_reason_codes_round_trip() {
+        let reasons = [
+            RejectReason::Other,
+            RejectReason::ServerFull,
+            RejectReason::Banned,
+            RejectReason::ShuttingDown,
+            RejectReason::VersionMismatch,
+            RejectReason::BadHandshake,
+        ];
+        for reason in reasons {
+            assert_eq!(RejectReason::from_code(reason.code()), reason);
+        }
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let s = TRICKY.to_string();
+        let cmds = [
+            Command::NewUser {
+                user: s.clone(),
+                pass: s.clone(),
+            },
+            Command::Login {
+                user: s.clone(),
+                pass: String::new(),
+            },
+            Command::Logout,
+            Command::Send { msg: s.clone() },
+            Command::Join { room: s.clone() },
+            Command::Leave { room: s.clone() },
+            Command::Rooms,
+            Command::Msg {
+                to: s.clone(),
+                text: s.clone(),
+            },
+            Command::Who,
+            Command::History { count: None },
+            Command::History { count: Some(7) },
+        ];
+        for cmd in cmds {
+            assert_eq!(Command::decode(&cmd.encode()).unwrap(), cmd);
+        }
+    }
+
+    #[test]
+    fn command_wrong_argument_count() {
+        let cases = [
+            ("newuser\x02alice", "expected 2 arguments but got 1"),
+            ("login", "expected 2 arguments but got 0"),
+            ("msg\x02a\x02b\x02c", "expected 2 arguments but got 3"),
+            ("logout\x02now", "expected 0 arguments but got 1"),
+            ("rooms\x02a", "expected 0 arguments but got 1"),
+            ("who\x02a\x02b", "expected 0 arguments but got 2"),
+            ("send", "expected 1 arguments but got 0"),
+            ("join\x02a\x02b", "expected 1 arguments but got 2"),
+            ("leave", "expected 1 arguments but got 0"),
+            ("history\x021\x022", "expected at most 1 argument but got 2"),
+            ("history\x02many", "Error. Invalid message count: many"),
+            ("dance", "Error. Command not recognized: dance"),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(
+                protocol_err(Command::decode(msg)),
+                expected,
+                "{:?}",
+                msg
+            );
+        }
+    }
+
+    #[test]
+    fn server_msg_round_trip() {
+        let s = TRICKY.to_string();
+        let msgs: Vec<ServerMsg> = vec![
+            Reply::Ok(s.clone()).into(),
+            Reply::Err(s.clone()).into(),
+            Reply::Ok(String::new()).into(),
+            Push::Message {
+                room: s.clone(),
+                from: s.clone(),
+                text: s.clone(),
+            }
+            .into(),
+            Push::Direct {
+                from: s.clone(),
+                text: s.clone(),
+            }
+            .into(),
+            Push::History {
+                time: 1_650_000_000,
+                room: s.clone(),
+                from: s.clone(),
+                text: s.clone(),
+            }
+            .into(),
+            Push::Unread {
+                time: -1,
+                from: s.clone(),
+                text: s.clone(),
+            }
+            .into(),
+            Push::Login { user: s.clone() }.into(),
+            Push::Logout { user: s.clone() }.into(),
+            Push::Shutdown {
+                grace: 30,
+                reason: Some(s.clone()),
+            }
+            .into(),
+            Push::Shutdown {
+                grace: 0,
+                reason: None,
+            }
+            .into(),
+            Push::Unknown {
+                kind: "teleport".to_string(),
+            }
+            .into(),
+        ];
+        for msg in msgs {
+            assert_eq!(ServerMsg::decode(&msg.encode()).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn server_msg_version_1_has_no_room() {
+        let msg: ServerMsg = Push::Message {
+            room: "#other".to_string(),
+            from: "alice".to_string(),
+            text: "hi".to_string(),
+        }
+        .into();
+        let encoded = msg.encode_version(1);
+        assert_eq!(encoded, "\x07msg\x02alice\x02hi");
+        assert_eq!(
+            ServerMsg::decode(&encoded).unwrap(),
+            Push::Message {
+                room: DEFAULT_ROOM.to_string(),
+                from: "alice".to_string(),
+                text: "hi".to_string(),
+            }
+            .into()
+        );
+
+        let reply: ServerMsg = Reply::Ok("done".to_string()).into();
+        assert_eq!(reply.encode_version(1), reply.encode());
+    }
+
+    #[test]
+    fn server_msg_invalid() {
+        let unknown = ServerMsg::decode("\x07teleport\x02a\x02b").unwrap();
+        assert_eq!(
+            unknown,
+            Push::Unknown {
+                kind: "teleport".to_string()
+            }
+            .into()
+        );
+
+        protocol_err(ServerMsg::decode(""));
+        protocol_err(ServerMsg::decode("?hello"));
+        protocol_err(ServerMsg::decode("\x07dm\x02alice"));
+        protocol_err(ServerMsg::decode("\x07login"));
+        protocol_err(ServerMsg::decode("\x07hist\x02now\x02#a\x02b\x02c"));
+        protocol_err(ServerMsg::decode("\x07shutdown\x02soon\x02"));
+    }
+}
@@ -1,12 +1,88 @@
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    io::{ErrorKind, Read},
+    os::unix::{io::AsRawFd, net::UnixStream},
+    sync::{atomic::AtomicBool, Arc},
+};
 
-use signal_hook;
+use libc::c_int;
+use signal_hook::{low_level, SigId};
 
 use crate::err::MyResult;
 
+/// The signals that ask the process to stop: an interrupt (e.g. Ctrl-C) or a
+/// termination request (e.g. from `kill` or a service manager).
+pub const STOP_SIGNALS: &[c_int] = &[libc::SIGINT, libc::SIGTERM];
+
 /// Setup an atomic flag to be enabled when the process receives an interrupt
-/// signal.
-pub fn setup_int_handler(stop_flag: &Arc<AtomicBool>) -> MyResult<()> {
-    signal_hook::flag::register(libc::SIGINT, stop_flag.clone())?;
-    Ok(())
+/// or termination signal (see `STOP_SIGNALS`).
+///
+/// The returned `SignalPipe` becomes readable at the same time, so that an
+/// event loop blocked waiting for file descriptors wakes up and can check the
+/// flag. The pipe must be kept alive for as long as the event loop runs.
+pub fn setup_int_handler(stop_flag: &Arc<AtomicBool>) -> MyResult<SignalPipe> {
+    for &signal in STOP_SIGNALS {
+        signal_hook::flag::register(signal, stop_flag.clone())?;
+    }
+    SignalPipe::new(STOP_SIGNALS)
+}
+
+/// A self-pipe that becomes readable whenever the process receives one of a
+/// set of signals.
+///
+/// The read end is meant to be waited on together with other file descriptors
+/// (see `sys::wait_readable()`). The signal handlers are removed when the pipe
+/// is dropped.
+pub struct SignalPipe {
+    read: UnixStream,
+    ids: Vec<SigId>,
+}
+
+impl Drop for SignalPipe {
+    fn drop(&mut self) {
+        // This also closes the write ends
+        for &id in &self.ids {
+            low_level::unregister(id);
+        }
+    }
+}
+
+impl SignalPipe {
+    /// Create a pipe that is written to whenever any of `signals` is
+    /// received.
+    pub fn new(signals: &[c_int]) -> MyResult<Self> {
+        let (read, write) = UnixStream::pair()?;
+        read.set_nonblocking(true)?;
+        let mut pipe = Self {
+            read,
+            ids: Vec::new(),
+        };
+        // Every handler owns its own handle to the write end
+        for &signal in signals {
+            let id = low_level::pipe::register(signal, write.try_clone()?)?;
+            pipe.ids.push(id);
+        }
+        Ok(pipe)
+    }
+
+    /// Return the file descriptor of the read end.
+    #[inline]
+    pub fn fd(&self) -> c_int {
+        self.read.as_raw_fd()
+    }
+
+    /// Read everything written to the pipe so that it is no longer readable.
+    pub fn drain(&self) -> MyResult<()> {
+        let mut buf = [0_u8; 64];
+        loop {
+            match (&self.read).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    return Ok(())
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
 }
@@ -0,0 +1,97 @@
+use std::{
+    fmt::{self, Debug},
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{err::MyResult, UserStore};
+
+/// Users (D)atabase (A)ccess (O)bject backed by an SQLite database.
+///
+/// The database file is created if it does not exist. Users are kept in one
+/// table, `users`, with the columns `name` and `hash`. SQLite commits every
+/// statement to disk before it returns, so changes are never lost.
+pub struct SqliteUsersDao {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteUsersDao {
+    /// Open (or create) the database at `path`.
+    pub fn open(path_ref: impl AsRef<Path>) -> MyResult<Self> {
+        let path = path_ref.as_ref().to_path_buf();
+        let conn = Connection::open(&path).map_err(|err| {
+            format!(
+                "failed to open users database: {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                name TEXT PRIMARY KEY NOT NULL,
+                hash TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Self { path, conn })
+    }
+}
+
+impl UserStore for SqliteUsersDao {
+    fn lookup(&self, user: &str) -> MyResult<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT hash FROM users WHERE name = ?1",
+                params![user],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn insert(&mut self, user: &str, hash: &str) -> MyResult<bool> {
+        let n_rows = self.conn.execute(
+            "INSERT OR IGNORE INTO users (name, hash) VALUES (?1, ?2)",
+            params![user, hash],
+        )?;
+        Ok(n_rows > 0)
+    }
+
+    fn update(&mut self, user: &str, hash: &str) -> MyResult<bool> {
+        let n_rows = self.conn.execute(
+            "UPDATE users SET hash = ?2 WHERE name = ?1",
+            params![user, hash],
+        )?;
+        Ok(n_rows > 0)
+    }
+
+    fn delete(&mut self, user: &str) -> MyResult<bool> {
+        let n_rows = self
+            .conn
+            .execute("DELETE FROM users WHERE name = ?1", params![user])?;
+        Ok(n_rows > 0)
+    }
+
+    fn list(&self) -> MyResult<Vec<String>> {
+        let mut stmt =
+            self.conn.prepare("SELECT name FROM users ORDER BY name")?;
+        let names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    fn flush(&mut self) -> MyResult<()> {
+        // Write out dirty pages of the cache, which only exist in the middle
+        // of a transaction
+        Ok(self.conn.cache_flush()?)
+    }
+}
+
+impl Debug for SqliteUsersDao {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!("SqliteUsersDao({})", self.path.display()))
+    }
+}
@@ -1,19 +1,32 @@
 use std::{
-    ffi::{CStr, CString},
-    io,
-    mem::size_of,
+    cell::RefCell,
+    ffi::{CStr, CString, OsStr},
+    fmt::{self, Display},
+    fs, io,
+    mem::{self, size_of},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr, str,
+    time::{Duration, Instant},
 };
 
 use libc::{
-    accept, bind, c_int, c_short, c_void, close, connect, in_addr, listen,
-    read, setsockopt, sockaddr, sockaddr_in, socket, write, AF_INET,
-    INADDR_LOOPBACK, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR,
+    accept, addrinfo, bind, c_int, c_void, close, connect, fcntl, freeaddrinfo,
+    gai_strerror, getaddrinfo, getsockopt, in6_addr, in_addr, listen, pollfd,
+    read, sa_family_t, setsockopt, sockaddr, sockaddr_in, sockaddr_in6,
+    sockaddr_storage, sockaddr_un, socket, socklen_t, write, AF_INET, AF_INET6,
+    AF_UNIX, AF_UNSPEC, EAI_SYSTEM, EINPROGRESS, F_GETFL, F_SETFL, O_NONBLOCK,
+    POLLOUT, SOCK_STREAM, SOL_SOCKET, SO_ERROR, SO_REUSEADDR,
 };
 use tracing::debug;
 
-use super::{hton, poll};
+use super::{errno_was_intr, hton};
 
-use crate::{err::MyResult, LISTEN_BACKLOG, MSG_MAX};
+use crate::{
+    err::{MyError, MyResult},
+    FRAME_MAX, LISTEN_BACKLOG,
+};
 
 macro_rules! SIZEOF {
     ($ty:ty) => {
@@ -25,53 +38,297 @@ macro_rules! SIZEOF {
 // Common
 //==============================================================================
 
-/// Represent a socket address.
+/// Size in bytes of the length header at the start of every frame.
+const FRAME_HEADER_SIZE: usize = size_of::<u32>();
+
+/// Size in bytes of each `read()` into a socket's receive buffer.
+const RECV_CHUNK_SIZE: usize = 4096;
+
+/// Encode `msg` as a frame, see `SocketCommon::send()`.
+fn encode_frame(msg: &str) -> MyResult<Vec<u8>> {
+    let payload = msg.as_bytes();
+    if payload.len() > FRAME_MAX {
+        return Err(format!(
+            "message too long: {} > {}",
+            payload.len(),
+            FRAME_MAX
+        )
+        .into());
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Represent an IPv4, IPv6 or Unix domain socket address.
 ///
 /// Utility methods are provided for easily passing this struct into socket API
-/// function calls.
+/// function calls. The address is kept in a `sockaddr_storage`, which is large
+/// enough for any family, along with the length of the actual address.
 pub struct SockAddr {
-    // Array has a method for casting to a mutable pointer, so use
-    // single-element array so it's easy to get a pointer to the data.
-    addr: [sockaddr_in; 1],
+    storage: sockaddr_storage,
+    len: socklen_t,
 }
 
 impl SockAddr {
-    /// Create a new `SockAddr` describing any address and the given port.
-    pub fn new(port: u16) -> Self {
-        Self {
-            #[cfg(target_os = "linux")]
-            addr: [sockaddr_in {
-                sin_family: AF_INET as u16,
-                sin_port: hton(port),
-                sin_addr: in_addr {
-                    s_addr: hton(INADDR_LOOPBACK),
-                },
-                sin_zero: [0; 8],
-            }],
-            #[cfg(target_os = "macos")]
-            addr: [sockaddr_in {
-                sin_len: 0,
-                sin_family: AF_INET as u8,
-                sin_port: hton(port),
-                sin_addr: in_addr {
-                    s_addr: hton(INADDR_LOOPBACK),
-                },
-                sin_zero: [0; 8],
-            }],
+    /// Create a new `SockAddr` describing the address `ip` and the given port.
+    ///
+    /// Use `Ipv4Addr::UNSPECIFIED` or `Ipv6Addr::UNSPECIFIED` to bind to every
+    /// local address.
+    pub fn new(ip: impl Into<IpAddr>, port: u16) -> Self {
+        SocketAddr::new(ip.into(), port).into()
+    }
+
+    /// Create a new `SockAddr` describing the Unix domain socket at `path`.
+    pub fn unix(path: impl AsRef<Path>) -> MyResult<Self> {
+        let path = path.as_ref();
+        let bytes = path.as_os_str().as_bytes();
+
+        let mut sun: sockaddr_un = unsafe { mem::zeroed() };
+        // The path must be NUL terminated
+        if bytes.len() >= sun.sun_path.len() {
+            return Err(format!(
+                "socket path is too long (max {} bytes): {}",
+                sun.sun_path.len() - 1,
+                path.display()
+            )
+            .into());
+        }
+        sun.sun_family = AF_UNIX as sa_family_t;
+        for (dst, &src) in sun.sun_path.iter_mut().zip(bytes) {
+            *dst = src as _;
         }
+
+        let mut sock_addr = Self::zero();
+        unsafe {
+            ptr::write(sock_addr.as_mut_ptr() as *mut sockaddr_un, sun);
+        }
+        sock_addr.len =
+            (size_of::<sa_family_t>() + bytes.len() + 1) as socklen_t;
+        Ok(sock_addr)
     }
 
     /// Create a new empty `SockAddr`.
     ///
-    /// Use this when a buffer is needed.
+    /// Use this when a buffer is needed, e.g. for `accept()`.
     pub fn zero() -> Self {
-        Self::new(0)
+        Self {
+            storage: unsafe { mem::zeroed() },
+            len: SIZEOF!(sockaddr_storage),
+        }
+    }
+
+    /// Return the address family, e.g. `AF_INET` or `AF_INET6`.
+    #[inline]
+    pub fn family(&self) -> c_int {
+        self.storage.ss_family as c_int
+    }
+
+    /// Return the IP address, or `None` if this is not an IPv4 or IPv6
+    /// address.
+    ///
+    /// An IPv4-mapped IPv6 address (which is what an IPv4 client of a
+    /// dual-stack listener has) is converted to the plain IPv4 address, so
+    /// that it compares equal to it, e.g. in `BANNED_ADDRS`.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.family() {
+            AF_INET | AF_INET6 => match self.to_std().ip() {
+                IpAddr::V6(ip) => {
+                    Some(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4))
+                }
+                ip => Some(ip),
+            },
+            _ => None,
+        }
+    }
+
+    /// Return the path of a Unix domain socket address, or `None` if this is
+    /// not one. The path of an unnamed socket (e.g. the client end of a
+    /// connection) is empty.
+    pub fn path(&self) -> Option<PathBuf> {
+        if self.family() != AF_UNIX {
+            return None;
+        }
+        let sun = unsafe { &*(self.as_ptr() as *const sockaddr_un) };
+        let max = (self.len as usize)
+            .saturating_sub(size_of::<sa_family_t>())
+            .min(sun.sun_path.len());
+        let bytes = sun.sun_path[..max]
+            .iter()
+            .map(|&c| c as u8)
+            .take_while(|&b| b != 0)
+            .collect::<Vec<_>>();
+        Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+    }
+
+    /// Return the length of the address.
+    #[inline]
+    pub fn len(&self) -> socklen_t {
+        self.len
+    }
+
+    /// Return whether the address is empty, i.e. it was created with `zero()`
+    /// and not filled in.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.family() == 0
     }
 
     /// Return a pointer suitable for use in socket API functions.
+    pub fn as_ptr(&self) -> *const sockaddr {
+        &self.storage as *const sockaddr_storage as *const sockaddr
+    }
+
+    /// Return a mutable pointer suitable for use in socket API functions.
     pub fn as_mut_ptr(&mut self) -> *mut sockaddr {
-        self.addr.as_mut_ptr() as *mut sockaddr
+        &mut self.storage as *mut sockaddr_storage as *mut sockaddr
+    }
+
+    /// Return a mutable pointer to the length of the address, for socket API
+    /// functions that fill in an address.
+    pub fn len_mut_ptr(&mut self) -> *mut socklen_t {
+        &mut self.len
     }
+
+    /// Convert this address to the standard library representation.
+    ///
+    /// An address that is neither IPv4 nor IPv6 (e.g. a Unix domain socket
+    /// address) is converted to the unspecified IPv4 address with port 0.
+    pub fn to_std(&self) -> SocketAddr {
+        match self.family() {
+            AF_INET => {
+                let addr = unsafe { &*(self.as_ptr() as *const sockaddr_in) };
+                SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                ))
+            }
+            AF_INET6 => {
+                let addr = unsafe { &*(self.as_ptr() as *const sockaddr_in6) };
+                SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    u32::from_be(addr.sin6_flowinfo),
+                    addr.sin6_scope_id,
+                ))
+            }
+            _ => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+        }
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let mut sock_addr = Self::zero();
+        match addr {
+            SocketAddr::V4(v4) => {
+                let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+                sin.sin_family = AF_INET as sa_family_t;
+                sin.sin_port = hton(v4.port());
+                sin.sin_addr = in_addr {
+                    s_addr: hton(u32::from(*v4.ip())),
+                };
+                unsafe {
+                    ptr::write(sock_addr.as_mut_ptr() as *mut sockaddr_in, sin);
+                }
+                sock_addr.len = SIZEOF!(sockaddr_in);
+            }
+            SocketAddr::V6(v6) => {
+                let mut sin6: sockaddr_in6 = unsafe { mem::zeroed() };
+                sin6.sin6_family = AF_INET6 as sa_family_t;
+                sin6.sin6_port = hton(v6.port());
+                sin6.sin6_flowinfo = hton(v6.flowinfo());
+                sin6.sin6_addr = in6_addr {
+                    s6_addr: v6.ip().octets(),
+                };
+                sin6.sin6_scope_id = v6.scope_id();
+                unsafe {
+                    ptr::write(
+                        sock_addr.as_mut_ptr() as *mut sockaddr_in6,
+                        sin6,
+                    );
+                }
+                sock_addr.len = SIZEOF!(sockaddr_in6);
+            }
+        }
+        sock_addr
+    }
+}
+
+impl Display for SockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path() {
+            Some(path) if path.as_os_str().is_empty() => write!(f, "unix:-"),
+            Some(path) => write!(f, "unix:{}", path.display()),
+            None => self.to_std().fmt(f),
+        }
+    }
+}
+
+/// Resolve `host`, which is either a host name or an IPv4 or IPv6 address, to
+/// every address it refers to, in the order returned by `getaddrinfo()`, with
+/// the port set to `port`.
+///
+/// Only IPv4 and IPv6 stream socket addresses are returned, and the list is
+/// never empty.
+pub fn resolve(host: &str, port: u16) -> MyResult<Vec<SocketAddr>> {
+    let node = CString::new(host)?;
+    let mut hints: addrinfo = unsafe { mem::zeroed() };
+    hints.ai_family = AF_UNSPEC;
+    hints.ai_socktype = SOCK_STREAM;
+
+    let mut res: *mut addrinfo = ptr::null_mut();
+    let ret =
+        unsafe { getaddrinfo(node.as_ptr(), ptr::null(), &hints, &mut res) };
+    if ret != 0 {
+        let err = if ret == EAI_SYSTEM {
+            io::Error::last_os_error().to_string()
+        } else {
+            unsafe { CStr::from_ptr(gai_strerror(ret)) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        return Err(format!("failed to resolve {}: {}", host, err).into());
+    }
+
+    let mut addrs = Vec::new();
+    let mut ai = res;
+    while !ai.is_null() {
+        let info = unsafe { &*ai };
+        let family = info.ai_family;
+        if (family == AF_INET || family == AF_INET6) && !info.ai_addr.is_null()
+        {
+            let mut addr = SockAddr::zero();
+            let len =
+                (info.ai_addrlen as usize).min(size_of::<sockaddr_storage>());
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    info.ai_addr as *const u8,
+                    addr.as_mut_ptr() as *mut u8,
+                    len,
+                );
+            }
+            addr.len = len as socklen_t;
+
+            let mut addr = addr.to_std();
+            addr.set_port(port);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        ai = info.ai_next;
+    }
+    unsafe { freeaddrinfo(res) };
+
+    if addrs.is_empty() {
+        return Err(
+            format!("failed to resolve {}: no addresses found", host).into()
+        );
+    }
+    debug!(host, ?addrs, "resolved host");
+    Ok(addrs)
 }
 
 /// An interface for performing common socket operations.
@@ -79,15 +336,20 @@ impl SockAddr {
 /// Implement syscall wrappers for socket operations that can be used on both
 /// the client-side and server-side are provided, including:
 /// - `close()`
-/// - `poll()`
 /// - `send()`
 /// - `recv()`
+///
+/// Messages are framed with a length header so that every `send()` maps to
+/// exactly one `recv()`, regardless of how the stream is segmented. Bytes of a
+/// partially received frame are kept in a per-socket buffer until the rest
+/// arrives.
 pub trait SocketCommon: From<c_int> {
-    /// Create a socket and return its file descriptor.
+    /// Create a stream socket of the address family `family` (e.g. `AF_INET`)
+    /// and return its file descriptor.
     ///
     /// **For internal use only.**
-    fn _create_raw() -> MyResult<c_int> {
-        let fd = unsafe { socket(AF_INET, SOCK_STREAM, 0) };
+    fn _create_raw(family: c_int) -> MyResult<c_int> {
+        let fd = unsafe { socket(family, SOCK_STREAM, 0) };
         if fd < 0 {
             let err = io::Error::last_os_error();
             Err(format!("failed to create socket: {}", err).into())
@@ -111,62 +373,170 @@ pub trait SocketCommon: From<c_int> {
         }
     }
 
-    /// Wrapper method that calls `poll()` on this socket.
-    fn poll(&self, events: c_short) -> MyResult<bool> {
-        poll(self.fd(), events)
+    /// Put the socket in non-blocking mode.
+    ///
+    /// Reads and writes then return at once instead of waiting for the peer,
+    /// so frames should be sent with `queue()` rather than `send()`.
+    fn set_nonblocking(&self) -> MyResult<()> {
+        let flags = unsafe { fcntl(self.fd(), F_GETFL) };
+        if flags < 0
+            || unsafe { fcntl(self.fd(), F_SETFL, flags | O_NONBLOCK) } < 0
+        {
+            let err = io::Error::last_os_error();
+            return Err(format!("failed to set O_NONBLOCK: {}", err).into());
+        }
+        Ok(())
     }
 
-    /// Wrapper for socket API `send()`.
+    /// Send `msg` as one frame.
+    ///
+    /// The frame header is the payload length as a big-endian `u32`. Short
+    /// writes are retried until the whole frame has been written, so this
+    /// blocks until the peer has made room for it.
     fn send(&self, msg: impl AsRef<str>) -> MyResult<()> {
-        // Make copy of msg and ensure it is null-terminated
-        let msg = CString::new(msg.as_ref())?;
+        let frame = encode_frame(msg.as_ref())?;
 
-        let buf = msg.as_ptr() as *const c_void;
-        let size = msg.as_bytes_with_nul().len();
-        if size > MSG_MAX {
-            return Err(
-                format!("message too long: {} > {}", size, MSG_MAX).into()
-            );
+        let mut sent = 0;
+        while sent < frame.len() {
+            let rest = &frame[sent..];
+            let buf = rest.as_ptr() as *const c_void;
+            let n_bytes = unsafe { write(self.fd(), buf, rest.len()) };
+            if n_bytes < 0 {
+                if errno_was_intr() {
+                    continue;
+                }
+                let err = io::Error::last_os_error();
+                return Err(format!("failed to send(): {}", err).into());
+            }
+            sent += n_bytes as usize;
         }
 
-        if unsafe { write(self.fd(), buf, size) < 0 } {
-            let err = io::Error::last_os_error();
-            Err(format!("failed to send(): {}", err).into())
-        } else {
-            Ok(())
-        }
+        Ok(())
     }
 
-    /// Wrapper for socket API `recv()`.
-    fn recv(&self, size: usize) -> MyResult<String> {
-        let mut buf = vec![0_u8; size];
-        let buf_ptr = buf.as_mut_ptr() as *mut c_void;
+    /// Return the buffer that holds bytes of queued frames which are not yet
+    /// written to the socket.
+    ///
+    /// **For internal use only.**
+    fn _send_buf(&self) -> &RefCell<Vec<u8>>;
+
+    /// Append `msg` as one frame to the send buffer, and write as much of the
+    /// buffer as the socket takes without blocking.
+    ///
+    /// This is the way to send on a non-blocking socket (see
+    /// `set_nonblocking()`). Whatever is left is written by `flush_send_buf()`
+    /// once the socket is ready for writing. If writing fails, the frame stays
+    /// queued and the next `flush_send_buf()` reports the error again.
+    fn queue(&self, msg: impl AsRef<str>) -> MyResult<()> {
+        let frame = encode_frame(msg.as_ref())?;
+        self._send_buf().borrow_mut().extend_from_slice(&frame);
+        self.flush_send_buf().map(|_| ())
+    }
+
+    /// Write as much of the send buffer as the socket takes without blocking,
+    /// and return whether the buffer is now empty.
+    fn flush_send_buf(&self) -> MyResult<bool> {
+        let mut buf = self._send_buf().borrow_mut();
+        let mut sent = 0;
+        let res = loop {
+            if sent == buf.len() {
+                break Ok(true);
+            }
+            let rest = &buf[sent..];
+            let rest_ptr = rest.as_ptr() as *const c_void;
+            let n_bytes = unsafe { write(self.fd(), rest_ptr, rest.len()) };
+            if n_bytes < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    io::ErrorKind::WouldBlock => break Ok(false),
+                    _ => break Err(format!("failed to send(): {}", err).into()),
+                }
+            }
+            sent += n_bytes as usize;
+        };
+        buf.drain(..sent);
+        res
+    }
+
+    /// Return the number of bytes in the send buffer that are not yet written
+    /// to the socket.
+    fn queued_len(&self) -> usize {
+        self._send_buf().borrow().len()
+    }
+
+    /// Return the buffer that holds received bytes which are not yet part of a
+    /// complete frame.
+    ///
+    /// **For internal use only.**
+    fn _recv_buf(&self) -> &RefCell<Vec<u8>>;
 
-        let n_bytes = unsafe { read(self.fd(), buf_ptr, size - 1) };
+    /// Perform one `read()` from the socket into the receive buffer.
+    ///
+    /// This blocks if no data is available, so event loops should only call it
+    /// once the socket is ready for reading. Complete frames can then be taken
+    /// with `recv_buffered()`. On a non-blocking socket with no data, nothing
+    /// is read.
+    fn fill_recv_buf(&self) -> MyResult<()> {
+        let mut chunk = [0_u8; RECV_CHUNK_SIZE];
+        let buf = chunk.as_mut_ptr() as *mut c_void;
+        let n_bytes = unsafe { read(self.fd(), buf, chunk.len()) };
         if n_bytes < 0 {
             let err = io::Error::last_os_error();
-            return Err(format!("failed to recv(): {}", err).into());
-        }
-
-        // Make sure buffer is null-terminated just in case it gets completely
-        // filled. This should never happen because the buffer is
-        // zero-initialized and the length given to recv() was size-1 so the
-        // last byte shouldn't be overwritten.
-        buf[size - 1] = 0;
-
-        // Convert message buffer to owned string:
-        // - Get the size of the buffer contents with one null byte at the end.
-        //   This is important because CStr considers the entire given value as
-        //   a string, so if there are extra null bytes at the end (i.e. the
-        //   buffer only gets partially filled) it will fail because of
-        //   "interior null bytes".
-        // - Get a slice of the buffer *with the terminating null byte*.
-        // - Attempt conversion from slice to CStr. This will fail if the buffer
-        //   contains invalid UTF-8 characters.
-        let len = buf.iter().position(|&c| c == 0).unwrap_or(size - 1) + 1;
-        let terminated_buf = buf.iter().cloned().take(len).collect::<Vec<_>>();
-        let msg = CStr::from_bytes_with_nul(&terminated_buf)?;
-        Ok(msg.to_str()?.to_string())
+            return match err.kind() {
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => {
+                    Ok(())
+                }
+                _ => Err(format!("failed to recv(): {}", err).into()),
+            };
+        }
+        let n_bytes = n_bytes as usize;
+        if n_bytes == 0 {
+            return Err(MyError::ConnectionClosed);
+        }
+        self._recv_buf()
+            .borrow_mut()
+            .extend_from_slice(&chunk[..n_bytes]);
+        Ok(())
+    }
+
+    /// Take the next complete frame out of the receive buffer, if there is
+    /// one, without reading from the socket.
+    fn recv_buffered(&self) -> MyResult<Option<String>> {
+        let mut buf = self._recv_buf().borrow_mut();
+        if buf.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0_u8; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&buf[..FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > FRAME_MAX {
+            return Err(
+                format!("frame too long: {} > {}", len, FRAME_MAX).into()
+            );
+        }
+
+        let end = FRAME_HEADER_SIZE + len;
+        if buf.len() < end {
+            return Ok(None);
+        }
+
+        let msg = str::from_utf8(&buf[FRAME_HEADER_SIZE..end])?.to_string();
+        buf.drain(..end);
+        Ok(Some(msg))
+    }
+
+    /// Receive one frame, blocking until it has arrived completely.
+    ///
+    /// Return `MyError::ConnectionClosed` if the peer closed the connection.
+    fn recv(&self) -> MyResult<String> {
+        loop {
+            if let Some(msg) = self.recv_buffered()? {
+                return Ok(msg);
+            }
+            self.fill_recv_buf()?;
+        }
     }
 }
 
@@ -174,6 +544,30 @@ pub trait SocketCommon: From<c_int> {
 // Server
 //==============================================================================
 
+/// An interface for listening sockets, so that a server can accept connections
+/// the same way regardless of the address family.
+///
+/// The sockets of accepted connections have the same type as the listening
+/// socket.
+pub trait Listener: SocketCommon {
+    /// Wrapper for socket API `accept()`.
+    ///
+    /// Return the socket for the new connection and the address of the peer.
+    fn accept(&self) -> MyResult<(Self, SockAddr)> {
+        let mut addr = SockAddr::zero();
+        let fd =
+            unsafe { accept(self.fd(), addr.as_mut_ptr(), addr.len_mut_ptr()) };
+
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            Err(format!("failed to accept(): {}", err).into())
+        } else {
+            debug!(sock = fd, peer = %addr, "accepted client");
+            Ok((fd.into(), addr))
+        }
+    }
+}
+
 /// A `SocketCommon` wrapper for a server-side socket.
 ///
 /// Calls `close()` when dropped.
@@ -181,9 +575,11 @@ pub trait SocketCommon: From<c_int> {
 /// Implement syscall wrappers for server-side socket operations, including:
 /// - `bind()`
 /// - `listen()`
-/// - `accept()`
+/// - `accept()` (see `Listener`)
 pub struct ServerSocket {
     sock: c_int,
+    recv_buf: RefCell<Vec<u8>>,
+    send_buf: RefCell<Vec<u8>>,
 }
 
 impl Drop for ServerSocket {
@@ -195,7 +591,11 @@ impl Drop for ServerSocket {
 impl From<c_int> for ServerSocket {
     /// Create a new `ServerSocket` from an existing file descriptor.
     fn from(sock: c_int) -> Self {
-        Self { sock }
+        Self {
+            sock,
+            recv_buf: RefCell::default(),
+            send_buf: RefCell::default(),
+        }
     }
 }
 
@@ -204,11 +604,23 @@ impl SocketCommon for ServerSocket {
     fn fd(&self) -> c_int {
         self.sock
     }
+
+    #[inline]
+    fn _recv_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.recv_buf
+    }
+
+    #[inline]
+    fn _send_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.send_buf
+    }
 }
 
 impl ServerSocket {
-    pub fn new() -> MyResult<Self> {
-        let fd = Self::_create_raw()?;
+    /// Create a socket for listening on addresses of the family `family`, e.g.
+    /// `SockAddr::family()`.
+    pub fn new(family: c_int) -> MyResult<Self> {
+        let fd = Self::_create_raw(family)?;
 
         // Set SO_REUSEADDR so a bind() doesn't fail on a socket that is in
         // the CLOSE_WAIT state.
@@ -228,11 +640,10 @@ impl ServerSocket {
     }
 
     /// Wrapper for socket API `bind()`.
-    pub fn bind(&self, addr: &mut SockAddr) -> MyResult<()> {
-        let size = SIZEOF!(sockaddr_in);
-        if unsafe { bind(self.sock, addr.as_mut_ptr(), size) < 0 } {
+    pub fn bind(&self, addr: &SockAddr) -> MyResult<()> {
+        if unsafe { bind(self.sock, addr.as_ptr(), addr.len()) < 0 } {
             let err = io::Error::last_os_error();
-            Err(format!("failed to bind(): {}", err).into())
+            Err(format!("failed to bind() to {}: {}", addr, err).into())
         } else {
             Ok(())
         }
@@ -247,23 +658,97 @@ impl ServerSocket {
             Ok(())
         }
     }
+}
 
-    /// Wrapper for socket API `accept()`.
-    pub fn accept(&self) -> MyResult<Self> {
-        let mut addr = SockAddr::zero();
-        // Use single-element array because it provides a method for
-        // converting to a mutable pointer.
-        let mut size = [SIZEOF!(sockaddr_in)];
+impl Listener for ServerSocket {}
 
-        let fd =
-            unsafe { accept(self.sock, addr.as_mut_ptr(), size.as_mut_ptr()) };
+/// A `SocketCommon` wrapper for a server-side Unix domain socket.
+///
+/// Calls `close()` when dropped, and removes the socket file if this socket
+/// was bound to it.
+///
+/// Implement syscall wrappers for server-side socket operations, including:
+/// - `bind()`
+/// - `listen()`
+/// - `accept()` (see `Listener`)
+pub struct UnixServerSocket {
+    sock: c_int,
+    recv_buf: RefCell<Vec<u8>>,
+    send_buf: RefCell<Vec<u8>>,
+    path: Option<PathBuf>,
+}
 
-        if fd < 0 {
+impl Drop for UnixServerSocket {
+    fn drop(&mut self) {
+        self.close();
+        if let Some(path) = &self.path {
+            debug!(path = %path.display(), "removing socket file");
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl From<c_int> for UnixServerSocket {
+    /// Create a new `UnixServerSocket` from an existing file descriptor.
+    fn from(sock: c_int) -> Self {
+        Self {
+            sock,
+            recv_buf: RefCell::default(),
+            send_buf: RefCell::default(),
+            path: None,
+        }
+    }
+}
+
+impl SocketCommon for UnixServerSocket {
+    #[inline]
+    fn fd(&self) -> c_int {
+        self.sock
+    }
+
+    #[inline]
+    fn _recv_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.recv_buf
+    }
+
+    #[inline]
+    fn _send_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.send_buf
+    }
+}
+
+impl Listener for UnixServerSocket {}
+
+impl UnixServerSocket {
+    /// Create a Unix domain socket for listening.
+    pub fn new() -> MyResult<Self> {
+        Ok(Self::_create_raw(AF_UNIX)?.into())
+    }
+
+    /// Wrapper for socket API `bind()`, which creates the socket file at
+    /// `path`.
+    ///
+    /// The file must not exist yet. Whoever may write to it may connect, so
+    /// access can be controlled with its permissions or those of its
+    /// directory.
+    pub fn bind(&mut self, path: impl AsRef<Path>) -> MyResult<()> {
+        let addr = SockAddr::unix(&path)?;
+        if unsafe { bind(self.sock, addr.as_ptr(), addr.len()) < 0 } {
             let err = io::Error::last_os_error();
-            Err(format!("failed to accept(): {}", err).into())
+            Err(format!("failed to bind() to {}: {}", addr, err).into())
         } else {
-            debug!(sock = fd, "accepted client");
-            Ok(fd.into())
+            self.path = Some(path.as_ref().to_path_buf());
+            Ok(())
+        }
+    }
+
+    /// Wrapper for socket API `listen()`.
+    pub fn listen(&self) -> MyResult<()> {
+        if unsafe { listen(self.sock, LISTEN_BACKLOG) < 0 } {
+            let err = io::Error::last_os_error();
+            Err(format!("failed to listen(): {}", err).into())
+        } else {
+            Ok(())
         }
     }
 }
@@ -280,6 +765,8 @@ impl ServerSocket {
 /// - `connect()`
 pub struct ClientSocket {
     sock: c_int,
+    recv_buf: RefCell<Vec<u8>>,
+    send_buf: RefCell<Vec<u8>>,
 }
 
 impl Drop for ClientSocket {
@@ -291,7 +778,11 @@ impl Drop for ClientSocket {
 impl From<c_int> for ClientSocket {
     /// Create a new `ServerSocket` from an existing file descriptor.
     fn from(sock: c_int) -> Self {
-        Self { sock }
+        Self {
+            sock,
+            recv_buf: RefCell::default(),
+            send_buf: RefCell::default(),
+        }
     }
 }
 
@@ -300,20 +791,306 @@ impl SocketCommon for ClientSocket {
     fn fd(&self) -> c_int {
         self.sock
     }
+
+    #[inline]
+    fn _recv_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.recv_buf
+    }
+
+    #[inline]
+    fn _send_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.send_buf
+    }
 }
 
 impl ClientSocket {
-    pub fn new() -> MyResult<Self> {
-        Ok(Self::_create_raw()?.into())
+    /// Create a socket for connecting to addresses of the family `family`,
+    /// e.g. `SockAddr::family()`.
+    pub fn new(family: c_int) -> MyResult<Self> {
+        Ok(Self::_create_raw(family)?.into())
     }
 
     /// Wrapper for socket API `connect()`.
-    pub fn connect(&self, addr: &mut SockAddr) -> MyResult<()> {
-        unsafe {
-            match connect(self.sock, addr.as_mut_ptr(), SIZEOF!(sockaddr_in)) {
-                0 => Ok(()),
-                _ => Err("failed to connect to socket".to_string().into()),
+    pub fn connect(&self, addr: &SockAddr) -> MyResult<()> {
+        if unsafe { connect(self.sock, addr.as_ptr(), addr.len()) } < 0 {
+            let err = io::Error::last_os_error();
+            Err(format!("failed to connect to {}: {}", addr, err).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Like `connect()`, but give up once `timeout` has passed.
+    ///
+    /// The socket is put in non-blocking mode while the connection is being
+    /// established, and back in blocking mode once it is.
+    pub fn connect_timeout(
+        &self,
+        addr: &SockAddr,
+        timeout: Duration,
+    ) -> MyResult<()> {
+        self.try_connect_timeout(addr, timeout).map_err(|err| {
+            format!("failed to connect to {}: {}", addr, err).into()
+        })
+    }
+
+    fn try_connect_timeout(
+        &self,
+        addr: &SockAddr,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        let flags = unsafe { fcntl(self.sock, F_GETFL) };
+        if flags < 0
+            || unsafe { fcntl(self.sock, F_SETFL, flags | O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        if unsafe { connect(self.sock, addr.as_ptr(), addr.len()) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(EINPROGRESS) {
+                return Err(err);
+            }
+            self.wait_connected(timeout)?;
+        }
+
+        if unsafe { fcntl(self.sock, F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait for a non-blocking `connect()` in progress to finish, and return
+    /// its result.
+    fn wait_connected(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut poll_fds = [pollfd {
+                fd: self.sock,
+                events: POLLOUT,
+                revents: 0,
+            }];
+            let n_ready = unsafe {
+                libc::poll(
+                    poll_fds.as_mut_ptr(),
+                    1,
+                    left.as_millis().min(c_int::MAX as u128) as c_int,
+                )
+            };
+
+            if n_ready < 0 {
+                if errno_was_intr() {
+                    continue;
+                }
+                return Err(io::Error::last_os_error());
+            }
+            if n_ready == 0 {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            break;
+        }
+
+        let mut error: c_int = 0;
+        let mut len = SIZEOF!(c_int);
+        let ret = unsafe {
+            getsockopt(
+                self.sock,
+                SOL_SOCKET,
+                SO_ERROR,
+                &mut error as *mut c_int as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else if error != 0 {
+            Err(io::Error::from_raw_os_error(error))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A `SocketCommon` wrapper for a client-side Unix domain socket.
+///
+/// Calls `close()` when dropped.
+///
+/// Implement syscall wrappers for client-side socket operations, including:
+/// - `connect()`
+pub struct UnixClientSocket {
+    sock: c_int,
+    recv_buf: RefCell<Vec<u8>>,
+    send_buf: RefCell<Vec<u8>>,
+}
+
+impl Drop for UnixClientSocket {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl From<c_int> for UnixClientSocket {
+    /// Create a new `UnixClientSocket` from an existing file descriptor.
+    fn from(sock: c_int) -> Self {
+        Self {
+            sock,
+            recv_buf: RefCell::default(),
+            send_buf: RefCell::default(),
+        }
+    }
+}
+
+impl SocketCommon for UnixClientSocket {
+    #[inline]
+    fn fd(&self) -> c_int {
+        self.sock
+    }
+
+    #[inline]
+    fn _recv_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.recv_buf
+    }
+
+    #[inline]
+    fn _send_buf(&self) -> &RefCell<Vec<u8>> {
+        &self.send_buf
+    }
+}
+
+impl UnixClientSocket {
+    /// Create a Unix domain socket for connecting.
+    pub fn new() -> MyResult<Self> {
+        Ok(Self::_create_raw(AF_UNIX)?.into())
+    }
+
+    /// Wrapper for socket API `connect()` to the socket file at `path`.
+    pub fn connect(&self, path: impl AsRef<Path>) -> MyResult<()> {
+        let addr = SockAddr::unix(path)?;
+        if unsafe { connect(self.sock, addr.as_ptr(), addr.len()) } < 0 {
+            let err = io::Error::last_os_error();
+            Err(format!("failed to connect to {}: {}", addr, err).into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        os::unix::{io::IntoRawFd, net::UnixStream},
+    };
+
+    use super::*;
+
+    /// Return a connected pair of the socket under test and its peer.
+    fn pair() -> (UnixClientSocket, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        (UnixClientSocket::from(ours.into_raw_fd()), theirs)
+    }
+
+    fn frame(msg: &str) -> Vec<u8> {
+        encode_frame(msg).unwrap()
+    }
+
+    #[test]
+    fn recv_buffered_split_frame() {
+        let (sock, mut peer) = pair();
+        let bytes = frame("hello");
+
+        // Split inside the header, then inside the payload
+        for part in [&bytes[..2], &bytes[2..6]] {
+            peer.write_all(part).unwrap();
+            sock.fill_recv_buf().unwrap();
+            assert_eq!(sock.recv_buffered().unwrap(), None);
+        }
+
+        peer.write_all(&bytes[6..]).unwrap();
+        sock.fill_recv_buf().unwrap();
+        assert_eq!(sock.recv_buffered().unwrap().as_deref(), Some("hello"));
+        assert_eq!(sock.recv_buffered().unwrap(), None);
+    }
+
+    #[test]
+    fn recv_buffered_coalesced_frames() {
+        let (sock, mut peer) = pair();
+        let mut bytes = frame("one");
+        bytes.extend(frame(""));
+        bytes.extend(frame("three"));
+        // Start of a fourth frame that is not complete yet
+        bytes.extend(&frame("four")[..5]);
+        peer.write_all(&bytes).unwrap();
+
+        sock.fill_recv_buf().unwrap();
+        assert_eq!(sock.recv_buffered().unwrap().as_deref(), Some("one"));
+        assert_eq!(sock.recv_buffered().unwrap().as_deref(), Some(""));
+        assert_eq!(sock.recv_buffered().unwrap().as_deref(), Some("three"));
+        assert_eq!(sock.recv_buffered().unwrap(), None);
+
+        peer.write_all(&frame("four")[5..]).unwrap();
+        assert_eq!(sock.recv().unwrap(), "four");
+    }
+
+    #[test]
+    fn recv_buffered_oversize_header() {
+        let (sock, mut peer) = pair();
+        let len = (FRAME_MAX + 1) as u32;
+        peer.write_all(&len.to_be_bytes()).unwrap();
+
+        sock.fill_recv_buf().unwrap();
+        let err = sock.recv_buffered().unwrap_err().to_string();
+        assert!(err.contains("frame too long"), "{}", err);
+    }
+
+    #[test]
+    fn recv_closed_connection() {
+        let (sock, mut peer) = pair();
+        peer.write_all(&frame("bye")).unwrap();
+        drop(peer);
+
+        assert_eq!(sock.recv().unwrap(), "bye");
+        assert!(matches!(sock.recv(), Err(MyError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn send_and_recv_frame() {
+        let (sock, peer) = pair();
+        let peer = UnixClientSocket::from(peer.into_raw_fd());
+        sock.send("ping").unwrap();
+        assert_eq!(peer.recv().unwrap(), "ping");
+
+        let too_long = "x".repeat(FRAME_MAX + 1);
+        assert!(sock.send(&too_long).is_err());
+    }
+
+    #[test]
+    fn queue_keeps_what_the_peer_does_not_take() {
+        let (sock, peer) = pair();
+        let peer = UnixClientSocket::from(peer.into_raw_fd());
+        sock.set_nonblocking().unwrap();
+        peer.set_nonblocking().unwrap();
+
+        // Far more than the socket buffer holds, so that some stays queued
+        let msg = "x".repeat(FRAME_MAX);
+        let n_frames = 64;
+        for _ in 0..n_frames {
+            sock.queue(&msg).unwrap();
+        }
+        assert!(sock.queued_len() > 0);
+        assert!(!sock.flush_send_buf().unwrap());
+
+        let mut n_received = 0;
+        while n_received < n_frames {
+            sock.flush_send_buf().unwrap();
+            peer.fill_recv_buf().unwrap();
+            while let Some(received) = peer.recv_buffered().unwrap() {
+                assert_eq!(received, msg);
+                n_received += 1;
             }
         }
+        assert_eq!(sock.queued_len(), 0);
+        assert!(sock.flush_send_buf().unwrap());
     }
 }
@@ -1,6 +1,9 @@
-use std::io;
+use std::{io, time::Duration};
 
-use libc::{self, c_int, c_short, pollfd};
+use libc::{
+    self, c_int, c_short, c_void, pollfd, POLLERR, POLLHUP, POLLIN, POLLNVAL,
+    POLLOUT,
+};
 use num_traits::{PrimInt, Unsigned};
 
 use crate::err::MyResult;
@@ -22,20 +25,108 @@ pub fn hton<U: PrimInt + Unsigned>(u: U) -> U {
     u.to_be()
 }
 
-/// Wrapper for `poll()`.
-pub fn poll(fd: c_int, events: c_short) -> MyResult<bool> {
-    let mut poll_fds = [pollfd {
-        fd,
-        events,
-        revents: 0,
-    }];
+/// Block until at least one of `fds` is ready for reading, or until `timeout`
+/// has passed if given, and return the ready file descriptors.
+///
+/// A file descriptor that was closed by the other end or has an error pending
+/// also counts as ready, so that the following read reports it.
+///
+/// If a signal interrupts the wait, an empty list is returned rather than an
+/// error. The caller should then check whatever its signal handlers set. To
+/// not miss a signal that arrives just before waiting, include the file
+/// descriptor of a `SignalPipe` in `fds`.
+pub fn wait_readable(
+    fds: &[c_int],
+    timeout: Option<Duration>,
+) -> MyResult<Vec<c_int>> {
+    wait_ready(fds, &[], timeout).map(|(readable, _)| readable)
+}
+
+/// Like `wait_readable()`, but also wait for any of `write_fds` to be ready
+/// for writing, and return the file descriptors ready for reading and those
+/// ready for writing.
+///
+/// A file descriptor may be in both `read_fds` and `write_fds`. One with an
+/// error pending counts as ready for both, so that the following read or
+/// write reports it.
+pub fn wait_ready(
+    read_fds: &[c_int],
+    write_fds: &[c_int],
+    timeout: Option<Duration>,
+) -> MyResult<(Vec<c_int>, Vec<c_int>)> {
+    let mut poll_fds = read_fds
+        .iter()
+        .map(|&fd| pollfd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    for &fd in write_fds {
+        match poll_fds.iter_mut().find(|pfd| pfd.fd == fd) {
+            Some(pfd) => pfd.events |= POLLOUT,
+            None => poll_fds.push(pollfd {
+                fd,
+                events: POLLOUT,
+                revents: 0,
+            }),
+        }
+    }
 
-    let n_ready = unsafe { libc::poll(poll_fds.as_mut_ptr(), 1, 0) };
+    let timeout = match timeout {
+        Some(t) => t.as_millis().min(c_int::MAX as u128) as c_int,
+        None => -1,
+    };
+
+    let n_ready = unsafe {
+        libc::poll(
+            poll_fds.as_mut_ptr(),
+            poll_fds.len() as libc::nfds_t,
+            timeout,
+        )
+    };
 
     if n_ready < 0 {
         let err = io::Error::last_os_error();
-        Err(format!("failed to poll: {}", err).into())
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        return Err(format!("failed to poll: {}", err).into());
+    }
+
+    let ready = |pfd: &pollfd, event: c_short| {
+        pfd.events & event != 0
+            && pfd.revents & (event | POLLERR | POLLHUP | POLLNVAL) != 0
+    };
+    let readable = poll_fds
+        .iter()
+        .filter(|pfd| ready(pfd, POLLIN))
+        .map(|pfd| pfd.fd)
+        .collect();
+    let writable = poll_fds
+        .iter()
+        .filter(|pfd| ready(pfd, POLLOUT))
+        .map(|pfd| pfd.fd)
+        .collect();
+    Ok((readable, writable))
+}
+
+/// Return whether `fd` refers to a terminal.
+#[inline]
+pub fn is_tty(fd: c_int) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Wrapper for `read()` on a raw file descriptor.
+///
+/// Return the number of bytes read into `buf`, where 0 means end-of-file.
+pub fn read_fd(fd: c_int, buf: &mut [u8]) -> MyResult<usize> {
+    let buf_ptr = buf.as_mut_ptr() as *mut c_void;
+    let n_bytes = unsafe { libc::read(fd, buf_ptr, buf.len()) };
+    if n_bytes < 0 {
+        let err = io::Error::last_os_error();
+        Err(format!("failed to read(): {}", err).into())
     } else {
-        Ok(n_ready > 0)
+        Ok(n_bytes as usize)
     }
 }
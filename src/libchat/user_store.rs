@@ -0,0 +1,177 @@
+use std::{
+    fmt::{self, Debug, Display},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{
+    err::MyResult,
+    password::{hash_password, verify_password},
+    UsersDao,
+};
+
+/// A storage backend for user accounts.
+///
+/// Implementations only store password *hashes*, never plaintext passwords.
+/// The provided methods `create_user()` and `verify()` take care of hashing
+/// and should be preferred over the raw `insert()` and `lookup()`.
+///
+/// Every method that modifies the store must have persisted the change by the
+/// time it returns.
+pub trait UserStore: Debug {
+    /// Return the password hash of `user`, or `None` if there is no such user.
+    fn lookup(&self, user: &str) -> MyResult<Option<String>>;
+
+    /// Insert `user` with the password hash `hash` and return whether the
+    /// operation was a success, i.e. whether there was not already an existing
+    /// user with the same name.
+    fn insert(&mut self, user: &str, hash: &str) -> MyResult<bool>;
+
+    /// Replace the password hash of `user` with `hash` and return whether the
+    /// user exists.
+    fn update(&mut self, user: &str, hash: &str) -> MyResult<bool>;
+
+    /// Delete `user` and return whether the user existed.
+    fn delete(&mut self, user: &str) -> MyResult<bool>;
+
+    /// Return the names of all users, sorted.
+    fn list(&self) -> MyResult<Vec<String>>;
+
+    /// Make sure everything is written to disk, e.g. before the server exits.
+    ///
+    /// Changes are already persisted by the methods that make them, so this
+    /// does nothing unless the backend keeps other data in memory.
+    fn flush(&mut self) -> MyResult<()> {
+        Ok(())
+    }
+
+    /// Hash `pass` and insert `user` with it. See `insert()`.
+    fn create_user(&mut self, user: &str, pass: &str) -> MyResult<bool> {
+        self.insert(user, &hash_password(pass)?)
+    }
+
+    /// Return whether `user` exists and `pass` is their password.
+    fn verify(&self, user: &str, pass: &str) -> MyResult<bool> {
+        Ok(match self.lookup(user)? {
+            Some(hash) => verify_password(pass, &hash),
+            None => false,
+        })
+    }
+}
+
+/// The kinds of `UserStore` that can be selected at startup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserStoreKind {
+    /// A text file with one `(user, hash)` per line (`UsersDao`).
+    Text,
+    /// An SQLite database file (`SqliteUsersDao`).
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl FromStr for UserStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!("unknown user store kind: {}", s)),
+        }
+    }
+}
+
+impl Display for UserStoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+/// Open the user store of the given kind at `path`.
+pub fn open_user_store(
+    kind: UserStoreKind,
+    path: impl AsRef<Path>,
+) -> MyResult<Box<dyn UserStore>> {
+    Ok(match kind {
+        UserStoreKind::Text => Box::new(UsersDao::from(path)?),
+        #[cfg(feature = "sqlite")]
+        UserStoreKind::Sqlite => Box::new(crate::SqliteUsersDao::open(path)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    /// Create, verify, change and reload users in a store of kind `kind`.
+    fn check_store(kind: UserStoreKind) {
+        let dir = env::temp_dir().join(format!(
+            "user-store-test-{}-{}",
+            process::id(),
+            kind
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.db");
+        // The text backend needs an existing file
+        fs::write(&path, "").unwrap();
+
+        let mut store = open_user_store(kind, &path).unwrap();
+        assert!(store.create_user("alice", "pass1").unwrap());
+        assert!(store.create_user("bob", "pass2").unwrap());
+        assert!(!store.create_user("alice", "other").unwrap());
+        assert!(store.verify("alice", "pass1").unwrap());
+        assert!(!store.verify("alice", "pass2").unwrap());
+        assert!(!store.verify("carol", "pass1").unwrap());
+
+        let hash = store.lookup("bob").unwrap().unwrap();
+        assert_ne!(hash, "pass2");
+        assert!(store
+            .update("bob", &hash_password("pass3").unwrap())
+            .unwrap());
+        assert!(!store.update("carol", &hash).unwrap());
+        assert!(store.delete("alice").unwrap());
+        assert!(!store.delete("alice").unwrap());
+        store.flush().unwrap();
+        drop(store);
+
+        let store = open_user_store(kind, &path).unwrap();
+        assert_eq!(store.list().unwrap(), ["bob"]);
+        assert!(store.verify("bob", "pass3").unwrap());
+        assert!(!store.verify("bob", "pass2").unwrap());
+        assert_eq!(store.lookup("alice").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn text_store() {
+        check_store(UserStoreKind::Text);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store() {
+        check_store(UserStoreKind::Sqlite);
+    }
+
+    #[test]
+    fn kind_names_round_trip() {
+        let kinds = [
+            UserStoreKind::Text,
+            #[cfg(feature = "sqlite")]
+            UserStoreKind::Sqlite,
+        ];
+        for kind in kinds {
+            assert_eq!(kind.to_string().parse::<UserStoreKind>(), Ok(kind));
+        }
+        assert!("csv".parse::<UserStoreKind>().is_err());
+    }
+}
@@ -1,49 +1,37 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::HashMap,
     fmt::{self, Debug},
-    fs::{File, OpenOptions},
-    io::{BufRead, BufReader, Write},
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
 };
 
 use regex::Regex;
 
-use crate::err::MyResult;
+use crate::{
+    err::MyResult,
+    password::{hash_password, is_hashed},
+    UserStore,
+};
 
 pub struct UsersDao {
     path: PathBuf,
     users: HashMap<String, String>,
-    dirty: bool,
-}
-
-impl Drop for UsersDao {
-    fn drop(&mut self) {
-        if !self.dirty {
-            return;
-        }
-
-        let mut f = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&self.path)
-            .unwrap_or_else(|e| {
-                panic!("failed to write users database file: {}", e)
-            });
-
-        for e in self.users.iter() {
-            f.write_all(format!("({}, {})\n", e.0, e.1).as_bytes())
-                .expect("failed to write to users database file");
-        }
-    }
 }
 
 /// Users (D)atabase (A)ccess (O)bject
 ///
-/// This type provides all methods necessary to interact with the users
-/// database.
+/// This type implements `UserStore` on top of a text file with one
+/// `(user, hash)` pair per line.
 ///
-/// If users are added to the database, then it will update the persistent (on
-/// -disk) storage when the object goes out of scope and is dropped.
+/// Every change is saved to the persistent (on-disk) storage immediately. The
+/// file is never modified in place: the new contents are written to a
+/// temporary file which is then renamed over the old one, so a crash leaves
+/// either the old or the new database, never a partially written one.
+///
+/// Passwords are stored as salted hashes (see `crate::password`). Plaintext
+/// passwords found in the database file are hashed when it is loaded and
+/// written back in hashed form right away.
 impl UsersDao {
     pub fn from(path_ref: impl AsRef<Path>) -> MyResult<Self> {
         let path = path_ref.as_ref().to_path_buf();
@@ -64,6 +52,7 @@ impl UsersDao {
         }
 
         let mut users = HashMap::<String, String>::new();
+        let mut upgraded = false;
 
         let reader = BufReader::new(File::open(&path)?);
         let line_re = Regex::new(r"^\s*\(\s*([^,]+)\s*,\s*([^)]+)\s*\)\s*$")?;
@@ -72,9 +61,14 @@ impl UsersDao {
             let line = line_res?;
             if let Some(m) = line_re.captures(&line) {
                 let username = m.get(1).unwrap().as_str().to_owned();
-                let password = m.get(2).unwrap().as_str();
+                let mut password = m.get(2).unwrap().as_str().to_owned();
+                if !is_hashed(&password) {
+                    // Upgrade plaintext password, it is saved below
+                    password = hash_password(&password)?;
+                    upgraded = true;
+                }
                 // TODO: error if duplicate username found
-                users.entry(username).or_default().push_str(password);
+                users.entry(username).or_default().push_str(&password);
             } else {
                 return Err(format!(
                     "invalid line in users database: {}:{}:{}",
@@ -86,30 +80,126 @@ impl UsersDao {
             }
         }
 
-        Ok(Self {
-            path,
-            users,
-            dirty: false,
-        })
+        let dao = Self { path, users };
+        if upgraded {
+            dao.save()?;
+        }
+        Ok(dao)
     }
 
-    /// Get an Entry for `user`.
-    pub fn entry(&mut self, user: impl AsRef<str>) -> Entry<String, String> {
-        self.users.entry(user.as_ref().to_string())
+    /// Apply `change` to the in-memory users and save the database, undoing
+    /// the change if saving fails.
+    fn modify<T>(
+        &mut self,
+        change: impl FnOnce(&mut HashMap<String, String>) -> T,
+    ) -> MyResult<T> {
+        let backup = self.users.clone();
+        let ret = change(&mut self.users);
+        if let Err(err) = self.save() {
+            self.users = backup;
+            return Err(err);
+        }
+        Ok(ret)
     }
 
-    /// Insert `user` with `pass` into the database and return whether the
-    /// operation was a success, i.e. whether was not already an existing user
-    /// with the same `name`.
-    pub fn insert<S: AsRef<str>>(&mut self, user: S, pass: S) -> bool {
-        self.dirty = true;
-        match self.users.entry(user.as_ref().to_string()) {
-            Entry::Occupied(_) => return false,
-            Entry::Vacant(ve) => {
-                ve.insert(pass.as_ref().to_string());
+    /// Write the database to disk.
+    ///
+    /// The users are written to a temporary file next to the database file,
+    /// which is flushed to disk and then atomically renamed over it. Finally
+    /// the directory is flushed so that the rename itself is durable.
+    fn save(&self) -> MyResult<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let write_tmp = || -> MyResult<()> {
+            let f = File::create(&tmp_path)?;
+            // Keep the permissions of the original file, which may restrict
+            // who can read the password hashes.
+            f.set_permissions(fs::metadata(&self.path)?.permissions())?;
+
+            // Sort the users so the file contents are stable between saves
+            let mut users = self.users.iter().collect::<Vec<_>>();
+            users.sort();
+
+            let mut writer = BufWriter::new(f);
+            for (user, pass) in users {
+                writeln!(writer, "({}, {})", user, pass)?;
             }
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            Ok(())
+        };
+
+        if let Err(err) = write_tmp() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(format!(
+                "failed to write users database file: {}: {}",
+                tmp_path.display(),
+                err
+            )
+            .into());
         }
-        true
+
+        fs::rename(&tmp_path, &self.path).map_err(|err| {
+            format!(
+                "failed to replace users database file: {}: {}",
+                self.path.display(),
+                err
+            )
+        })?;
+
+        let dir = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+impl UserStore for UsersDao {
+    fn lookup(&self, user: &str) -> MyResult<Option<String>> {
+        Ok(self.users.get(user).cloned())
+    }
+
+    fn insert(&mut self, user: &str, hash: &str) -> MyResult<bool> {
+        if self.users.contains_key(user) {
+            return Ok(false);
+        }
+        self.modify(|users| {
+            users.insert(user.to_string(), hash.to_string());
+        })?;
+        Ok(true)
+    }
+
+    fn update(&mut self, user: &str, hash: &str) -> MyResult<bool> {
+        if !self.users.contains_key(user) {
+            return Ok(false);
+        }
+        self.modify(|users| {
+            users.insert(user.to_string(), hash.to_string());
+        })?;
+        Ok(true)
+    }
+
+    fn delete(&mut self, user: &str) -> MyResult<bool> {
+        if !self.users.contains_key(user) {
+            return Ok(false);
+        }
+        self.modify(|users| {
+            users.remove(user);
+        })?;
+        Ok(true)
+    }
+
+    fn list(&self) -> MyResult<Vec<String>> {
+        let mut names = self.users.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
     }
 }
 
@@ -118,3 +208,89 @@ impl Debug for UsersDao {
         f.write_fmt(format_args!("{:?}", self.users))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Return the path of a users database file with `contents` in an empty
+    /// directory for the test called `name`.
+    fn test_db(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "users-dao-test-{}-{}",
+            process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.txt");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn remove_test_db(path: &Path) {
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn plaintext_passwords_are_hashed() {
+        let path = test_db("migrate", "(Tom, Tom11)\n(bob.s, pass1)\n");
+        let dao = UsersDao::from(&path).unwrap();
+
+        // The file is rewritten with hashes only
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(
+            !text.contains("Tom11") && !text.contains("pass1"),
+            "{}",
+            text
+        );
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for (line, user) in lines.iter().zip(["Tom", "bob.s"]) {
+            let prefix = format!("({}, $argon2", user);
+            assert!(line.starts_with(&prefix), "{}", line);
+        }
+
+        // Login works before and after reloading
+        assert!(dao.verify("Tom", "Tom11").unwrap());
+        assert!(!dao.verify("Tom", "Tom12").unwrap());
+        let dao = UsersDao::from(&path).unwrap();
+        assert!(dao.verify("bob.s", "pass1").unwrap());
+        assert!(!dao.verify("nobody", "pass1").unwrap());
+        // Nothing is left to migrate, so the file stays the same
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+
+        remove_test_db(&path);
+    }
+
+    #[test]
+    fn failed_save_keeps_state() {
+        let path = test_db("rollback", "");
+        let mut dao = UsersDao::from(&path).unwrap();
+        assert!(dao.create_user("alice", "pass1").unwrap());
+
+        // A directory in place of the temporary file makes saving fail
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::create_dir(&tmp_path).unwrap();
+
+        assert!(dao.create_user("bob", "pass2").is_err());
+        assert!(dao.delete("alice").is_err());
+        assert!(dao
+            .update("alice", &hash_password("pass3").unwrap())
+            .is_err());
+
+        assert_eq!(dao.list().unwrap(), ["alice"]);
+        assert!(dao.verify("alice", "pass1").unwrap());
+        assert_eq!(dao.lookup("bob").unwrap(), None);
+
+        fs::remove_dir(&tmp_path).unwrap();
+        let dao = UsersDao::from(&path).unwrap();
+        assert_eq!(dao.list().unwrap(), ["alice"]);
+        assert!(dao.verify("alice", "pass1").unwrap());
+
+        remove_test_db(&path);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{
+    MSG_MAX, PASSWORD_MAX, PASSWORD_MIN, ROOM_MAX, USERNAME_MAX, USERNAME_MIN,
+};
+
+/// Error message for a command that may only be executed when logged out.
+pub const E_NOT_LOGGED_OUT: &str = "Denied. Must be logged out.";
+
+/// Error message for a command that may only be executed when logged in.
+pub const E_NOT_LOGGED_IN: &str = "Denied. Please login first.";
+
+/// Check that `user` is a valid user name.
+///
+/// User names must be `USERNAME_MIN..=USERNAME_MAX` characters long. The
+/// error is a message suitable for showing to the user.
+pub fn check_username(user: &str) -> Result<(), String> {
+    if user.len() < USERNAME_MIN || user.len() > USERNAME_MAX {
+        Err(format!(
+            "Error. User name must be {}-{} characters",
+            USERNAME_MIN, USERNAME_MAX
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that `user` only contains the characters allowed in the names of new
+/// accounts, which are ASCII letters, digits, `_` and `-`.
+///
+/// Accounts created before this rule may have other names and keep working,
+/// so this must only be checked when creating an account. The error is a
+/// message suitable for showing to the user.
+pub fn check_new_username(user: &str) -> Result<(), String> {
+    if user
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Ok(())
+    } else {
+        Err(
+            "Error. User name may only contain letters, digits, '_' and '-'"
+                .to_string(),
+        )
+    }
+}
+
+/// Check that `pass` is a valid password.
+///
+/// Passwords must be `PASSWORD_MIN..=PASSWORD_MAX` characters long. The error
+/// is a message suitable for showing to the user.
+pub fn check_password(pass: &str) -> Result<(), String> {
+    if pass.len() < PASSWORD_MIN || pass.len() > PASSWORD_MAX {
+        Err(format!(
+            "Error. Password must be {}-{} characters",
+            PASSWORD_MIN, PASSWORD_MAX
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that `room` is a valid room name.
+///
+/// Room names are a `#` followed by 1 to `ROOM_MAX` ASCII letters, digits, `_`
+/// and `-`. The error is a message suitable for showing to the user.
+pub fn check_room(room: &str) -> Result<(), String> {
+    let valid = match room.strip_prefix('#') {
+        Some(name) => {
+            !name.is_empty()
+                && name.len() <= ROOM_MAX
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        }
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Error. Room name must be '#' followed by 1-{} letters, digits, \
+             '_' and '-'",
+            ROOM_MAX
+        ))
+    }
+}
+
+/// Check that `msg` is a valid chat message.
+///
+/// Messages must not be blank and may be at most `MSG_MAX` bytes long. The
+/// error is a message suitable for showing to the user.
+pub fn check_message(msg: &str) -> Result<(), String> {
+    if msg.trim().is_empty() {
+        Err("Error. Message must not be empty".to_string())
+    } else if msg.len() > MSG_MAX {
+        Err(format!("Error. Message must be at most {} bytes", MSG_MAX))
+    } else {
+        Ok(())
+    }
+}